@@ -0,0 +1,14 @@
+// cargo +nightly fuzz run client_hello
+// 任意输入都不能 panic，解析成功时指纹也要能算出来
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ooproxy::tls;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = tls::parse_tls_record(data);
+    let _ = tls::parse_handshake(data);
+    if let Ok(hello) = tls::parse_client_hello(data) {
+        let _ = hello.ja3();
+        let _ = hello.ja4();
+    }
+});
@@ -0,0 +1,12 @@
+// cargo +nightly fuzz run quic_initial
+// 改过的包基本都过不了 AEAD，主要覆盖 long header、varint 和多个包合在一起的处理
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ooproxy::quic;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = quic::is_initial(data);
+    if let Ok(hello) = quic::parse_initial(data) {
+        let _ = hello.ja4_quic();
+    }
+});
@@ -0,0 +1,24 @@
+// cargo +nightly fuzz run sniff
+// 按透明代理的方式依次喂给所有协议
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ooproxy::{
+    config::SniffConfig,
+    sniff::{self, Protocol},
+};
+
+fuzz_target!(|data: &[u8]| {
+    let sniffers: Vec<SniffConfig> = [
+        Protocol::Tls,
+        Protocol::Http,
+        Protocol::Ssh,
+        Protocol::Bittorrent,
+    ]
+    .iter()
+    .map(|&protocol| SniffConfig {
+        protocol,
+        override_dest: true,
+    })
+    .collect();
+    let _ = sniff::sniff(data, &sniffers);
+});
@@ -1,3 +1,5 @@
+// 练手的草稿代码，不参与 lint
+#![allow(dead_code, unused, clippy::all)]
 use std::{cell::{Cell, RefCell}, ops::Deref};
 #[derive(Debug)]
 pub struct Myself<'a> {
@@ -0,0 +1,309 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use log::{debug, warn};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::{timeout, timeout_at, Instant},
+};
+
+use crate::{
+    client::{Address, Destination},
+    config::Config,
+    protocols::socks5::{self, Reply, ReplyError},
+    router::Outbound,
+    stream::pipe,
+    udp::{canonical_addr, canonical_ip},
+    upstream::{self, UpstreamGuard},
+};
+
+// BIND 的两次回复
+// 第一次告诉 client 在哪个地址等待连接，第二次告诉 client 连进来的是谁
+// 之后 control 连接就用来传数据，比如主动模式 FTP 的数据连接
+// outbound 是按 DST 匹配路由规则的结果
+// https://tools.ietf.org/html/rfc1928#section-4
+pub async fn bind(
+    mut control: TcpStream,
+    config: Arc<Config>,
+    dest: Destination,
+    outbound: Outbound,
+    label: String,
+) -> io::Result<()> {
+    match connect_upstream(&config, &dest, outbound).await {
+        Ok(Some((remote, bound, guard))) => {
+            debug!(
+                "{} bind {} on {} through upstream {}",
+                label,
+                dest,
+                bound,
+                guard.upstream()
+            );
+            control
+                .write_all(&socks5::build_reply(0x00, &bound)?)
+                .await?;
+            relay_upstream(control, remote, &config, &label).await
+        }
+        Ok(None) => bind_local(control, &config, &dest, &label).await,
+        Err(err) => {
+            control.write_all(&error_reply((&err).into())?).await?;
+            Err(err)
+        }
+    }
+}
+
+fn error_reply(err: ReplyError) -> io::Result<Vec<u8>> {
+    Reply::error(err).to_bytes()
+}
+
+// upstream 的第二个回复原样转给 client，然后开始 pipe
+async fn relay_upstream(
+    mut control: TcpStream,
+    mut remote: TcpStream,
+    config: &Config,
+    label: &str,
+) -> io::Result<()> {
+    let peer = match timeout(config.timeouts.bind, socks5::read_reply(&mut remote)).await {
+        Ok(Ok(peer)) => peer,
+        Ok(Err(err)) => {
+            // upstream 回复的错误码原样转给 client
+            control.write_all(&error_reply((&err).into())?).await?;
+            return Err(err);
+        }
+        Err(_) => {
+            control
+                .write_all(&error_reply(ReplyError::TtlExpired)?)
+                .await?;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no incoming connection for bind",
+            ));
+        }
+    };
+    debug!("{} bind accepted {}", label, peer);
+    control
+        .write_all(&socks5::build_reply(0x00, &peer)?)
+        .await?;
+    pipe(control, remote).await
+}
+
+// 没有 upstream 支持 BIND 时在本机监听
+async fn bind_local(
+    mut control: TcpStream,
+    config: &Config,
+    dest: &Destination,
+    label: &str,
+) -> io::Result<()> {
+    let local_ip = match outbound_ip(dest).await {
+        Some(ip) => ip,
+        None => canonical_ip(control.local_addr()?.ip()),
+    };
+    let listener = match TcpListener::bind((local_ip, 0)).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            control
+                .write_all(&error_reply(ReplyError::GeneralFailure)?)
+                .await?;
+            return Err(err);
+        }
+    };
+    let bound = canonical_addr(listener.local_addr()?);
+    debug!("{} bind {} on {}", label, dest, bound);
+    control
+        .write_all(&socks5::build_reply(0x00, &bound.into())?)
+        .await?;
+    let deadline = Instant::now() + config.timeouts.bind;
+    let (incoming, peer) = loop {
+        let (incoming, peer) = match timeout_at(deadline, listener.accept()).await {
+            Ok(accepted) => accepted?,
+            Err(_) => {
+                control
+                    .write_all(&error_reply(ReplyError::TtlExpired)?)
+                    .await?;
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no incoming connection for bind",
+                ));
+            }
+        };
+        let peer = canonical_addr(peer);
+        // DST.ADDR 是 client 期望连进来的地址，不是它的连接直接丢掉
+        match dest.host {
+            Address::Ip(ip) if !ip.is_unspecified() && canonical_ip(ip) != peer.ip() => {
+                warn!("{} bind drop unexpected connection from {}", label, peer);
+            }
+            _ => break (incoming, peer),
+        }
+    };
+    // 只接受一个连接
+    drop(listener);
+    debug!("{} bind accepted {}", label, peer);
+    control
+        .write_all(&socks5::build_reply(0x00, &peer.into())?)
+        .await?;
+    pipe(control, incoming).await
+}
+
+// 用 connect 过的 udp socket 问内核去 dest 走哪个本地地址，不会真的发包
+// 对端看到的就是这个地址
+async fn outbound_ip(dest: &Destination) -> Option<IpAddr> {
+    let ip = match dest.host {
+        Address::Ip(ip) if !ip.is_unspecified() => canonical_ip(ip),
+        _ => return None,
+    };
+    let local: SocketAddr = match ip {
+        IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await.ok()?;
+    // 端口不重要，但不能是 0
+    socket.connect((ip, dest.port.max(1))).await.ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+// proxy 按 upstream_policy 的顺序找一个支持 BIND 的 upstream
+// 返回连接、upstream 监听的地址
+// 没有配置支持 BIND 的 upstream 或者 direct 时返回 None，在本机监听
+async fn connect_upstream(
+    config: &Arc<Config>,
+    dest: &Destination,
+    outbound: Outbound,
+) -> io::Result<Option<(TcpStream, Destination, UpstreamGuard)>> {
+    let candidates = match upstream::outbound_candidates(config, outbound, |u| u.bind, "bind")? {
+        Some(candidates) => candidates,
+        None => return Ok(None),
+    };
+    let mut last_err = None;
+    for index in candidates {
+        let upstream = &config.upstreams[index];
+        if !upstream.bind {
+            continue;
+        }
+        let result = timeout(config.timeouts.handshake, async {
+            let mut remote = timeout(config.timeouts.connect, TcpStream::connect(upstream.addr))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timeout"))??;
+            let bound = socks5::bind(&mut remote, dest, upstream.credentials()).await?;
+            let bound = match bound.host {
+                // 回复全 0 时用 upstream 自己的地址
+                Address::Ip(ip) if ip.is_unspecified() => {
+                    SocketAddr::new(upstream.addr.ip(), bound.port).into()
+                }
+                _ => bound,
+            };
+            Ok::<_, io::Error>((remote, bound))
+        })
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "bind timeout")));
+        match result {
+            Ok((remote, bound)) => {
+                let guard = UpstreamGuard::new(config.clone(), index);
+                return Ok(Some((remote, bound, guard)));
+            }
+            Err(err) => {
+                warn!("upstream {} bind failed: {}", upstream, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    match last_err {
+        Some(err) => Err(err),
+        None => Ok(None),
+    }
+}
+
+// 没有 upstream 时在本机监听：两次回复，然后 control 和连进来的连接互相转发
+#[tokio::test]
+async fn test_bind_local() {
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut control = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    // FTP 的 PORT 命令之前不知道对端的端口，只给出地址
+    let dest: Destination = SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into();
+    // 没有配置 upstream 时 proxy 也在本机监听
+    let task = tokio::spawn(bind(
+        accepted,
+        Arc::new(Config::default()),
+        dest,
+        Outbound::Proxy,
+        String::from("test"),
+    ));
+
+    let bound = match socks5::read_reply(&mut control).await.unwrap() {
+        Destination {
+            host: Address::Ip(ip),
+            port,
+        } => SocketAddr::new(ip, port),
+        bound => panic!("unexpected bound address {}", bound),
+    };
+    assert_eq!(bound.ip(), IpAddr::from(Ipv4Addr::LOCALHOST));
+    let mut incoming = TcpStream::connect(bound).await.unwrap();
+    let peer = socks5::read_reply(&mut control).await.unwrap();
+    assert_eq!(peer, incoming.local_addr().unwrap().into());
+
+    incoming.write_all(b"220 ready").await.unwrap();
+    let mut buf = [0u8; 9];
+    control.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"220 ready");
+    control.write_all(b"QUIT").await.unwrap();
+    let mut buf = [0u8; 4];
+    incoming.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"QUIT");
+
+    drop(incoming);
+    drop(control);
+    timeout(Duration::from_secs(5), task)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+// DST.ADDR 之外的地址连进来时丢掉，超时后回复 TTL expired
+#[tokio::test]
+async fn test_bind_local_unexpected_peer() {
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut control = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    let mut config = Config::default();
+    config.timeouts.bind = Duration::from_millis(300);
+    let dest: Destination = SocketAddr::from(([127, 0, 0, 2], 21)).into();
+    let task = tokio::spawn(bind(
+        accepted,
+        Arc::new(config),
+        dest,
+        Outbound::Direct,
+        String::from("test"),
+    ));
+    let bound = socks5::read_reply(&mut control).await.unwrap();
+    let bound = match bound.host {
+        Address::Ip(ip) => SocketAddr::new(ip, bound.port),
+        _ => panic!("unexpected bound address {}", bound),
+    };
+    // 从 127.0.0.1 连进来，连接被关掉
+    let mut unexpected = TcpStream::connect(bound).await.unwrap();
+    let mut buf = [0u8; 1];
+    assert_eq!(unexpected.read(&mut buf).await.unwrap(), 0);
+    let err = socks5::read_reply(&mut control).await.unwrap_err();
+    assert_eq!(
+        ReplyError::from_io_error(&err),
+        Some(ReplyError::TtlExpired)
+    );
+    assert_eq!(
+        task.await.unwrap().unwrap_err().kind(),
+        io::ErrorKind::TimedOut
+    );
+}
@@ -1,28 +1,37 @@
 use std::sync::Arc;
 use std::{
     borrow::Cow,
-    io,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
-    time::Duration,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     vec,
 };
 
 use bytes::{Bytes, BytesMut};
-use log::{debug, error, info};
+use log::{debug, info, warn};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    time::timeout,
+    time::{timeout, timeout_at, Instant},
 };
 
-use crate::protocols::handshake;
-use crate::{config::Config, stream::pipe};
+use crate::linux::{get_original_address_v4, get_original_address_v6};
+use crate::protocols::{
+    detect::{peek_protocol, Protocol},
+    handshake, http, socks4,
+    socks5::{self, Reply, REP_SUCCEEDED},
+};
+use crate::upstream::{self, Upstream, UpstreamGuard};
 use crate::{
-    linux::{get_original_address_v4, get_original_address_v6},
-    tls,
+    bind,
+    config::{Config, Listener},
+    direct,
+    router::{Outbound, Session},
+    sniff::{self, SniffResult, Sniffed},
+    stream::pipe,
+    udp,
 };
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Address {
     Ip(IpAddr),
     Domain(Box<str>),
@@ -46,11 +55,30 @@ impl From<String> for Address {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct Destination {
     pub host: Address,
     pub port: u16,
 }
 
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Ip(ip) => write!(f, "{}", ip),
+            Address::Domain(domain) => write!(f, "{}", domain),
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host {
+            Address::Ip(IpAddr::V6(ip)) => write!(f, "[{}]:{}", ip, self.port),
+            ref host => write!(f, "{}:{}", host, self.port),
+        }
+    }
+}
+
 impl From<SocketAddr> for Destination {
     fn from(addr: SocketAddr) -> Self {
         Destination {
@@ -84,12 +112,44 @@ pub struct Client {
     left: TcpStream,
     src: SocketAddr,
     pub dest: Destination,
-    from_port: u16,
     pending_data: Option<Bytes>,
+    // 转发的普通 http 请求还没读到的请求体，只转发这一个请求
+    http_body: Option<http::RequestBody>,
+    // 正在使用的 upstream，跟随 client 一起 drop
+    upstream: Option<UpstreamGuard>,
+    // 从哪个 listener 进来的，config.listeners 的下标
+    listener: usize,
+    // socks5 认证通过的用户名
+    pub user: Option<String>,
+    pub command: Command,
+    pub inbound: Inbound,
+    // 透明代理时嗅探出的协议和域名
+    pub sniffed: Option<Sniffed>,
+}
+
+// client 是怎么连进来的，决定连上 upstream 之后怎么回复它
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Inbound {
+    // iptables REDIRECT，不需要回复
+    Transparent,
+    Socks5,
+    Socks4,
+    // tunnel 是 CONNECT 请求，否则是转发的普通 http 请求
+    Http { tunnel: bool },
+}
+
+// socks5 请求的 CMD，透明代理的连接都是 Connect
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Connect,
+    // dest 是 client 声明的发送 udp 的地址
+    UdpAssociate,
+    // dest 是 client 期望连进来的地址
+    Bind,
 }
 
 // 归一化处理，统一用 ipv6 比较
-fn normalize_socket_addr(socket: &SocketAddr) -> Cow<SocketAddr> {
+fn normalize_socket_addr(socket: &SocketAddr) -> Cow<'_, SocketAddr> {
     match socket {
         SocketAddr::V4(sock) => {
             let addr = sock.ip().to_ipv6_mapped();
@@ -103,9 +163,13 @@ fn error_invalid_input<T>(msg: &'static str) -> io::Result<T> {
     Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
 }
 impl Client {
-    pub async fn from_socket(mut peer_left: TcpStream, config: Arc<Config>) -> io::Result<Self> {
+    // listener 是 config.listeners 的下标
+    pub async fn from_socket(
+        mut peer_left: TcpStream,
+        config: Arc<Config>,
+        listener: usize,
+    ) -> io::Result<Self> {
         let left_src = peer_left.peer_addr()?;
-        let src_port = peer_left.local_addr()?.port();
         let dest = get_original_address_v4(&peer_left)
             .map(SocketAddr::V4)
             .or_else(|_| get_original_address_v6(&peer_left).map(SocketAddr::V6))
@@ -118,141 +182,458 @@ impl Client {
             normalize_socket_addr(&dest) != normalize_socket_addr(&peer_left.local_addr()?);
 
         debug!("local {} dest{}", peer_left.local_addr()?, dest);
-        let dest = if cfg!(target_os = "linux") && is_nated {
-            dest.into()
-        } else {
-            let ver = peer_left.read_u8().await?;
-            if ver != 0x05 {
-                return error_invalid_input("Neither a NATed or SOCKSv5 connection ");
-            }
-            // 作为 socks server 进行握手
-            let n_methods = peer_left.read_u8().await?;
-            let mut buf = vec![0u8; n_methods as usize];
-            peer_left.read_exact(&mut buf).await?;
-            if buf.iter().find(|&&m| m == 0).is_none() {
-                return error_invalid_input("Socksv5, Only no auth supported");
-            }
-            peer_left.write_all(&[0x05, 0x00]).await?;
-            buf.resize(4, 0);
-            peer_left.read_exact(&mut buf).await?;
-            if buf[0..2] != [0x05, 0x01] {
-                return error_invalid_input("Socksv5, CONNECT is required");
-            }
-            let addr: Address = match buf[3] {
-                0x01 => {
-                    // ipv4
-                    let mut buf = [0u8; 4];
-                    peer_left.read_exact(&mut buf).await?;
-                    buf.into()
-                }
-                0x03 => {
-                    // domain
-                    // socks v5 domain first byte 是 domain 长度
-                    let domain_len = peer_left.read_u8().await? as usize;
-                    buf.resize(domain_len, 0);
-                    let raw_ipv4 = peer_left.read_exact(&mut buf).await?;
-                    let domain = String::from_utf8(buf).map_err(|_| {
-                        io::Error::new(io::ErrorKind::InvalidInput, "Socksv5: invalid domain name")
-                    })?;
-                    domain.into()
-                }
-                0x04 => {
-                    // ipv6
-                    let mut buf = [0u8; 16];
-                    peer_left.read_exact(&mut buf).await?;
-                    buf.into()
+        let listener_config = &config.listeners[listener];
+        let mut pending_data = None;
+        let mut http_body = None;
+        let (dest, user, command, inbound): (Destination, _, _, _) =
+            if cfg!(target_os = "linux") && is_nated {
+                (dest.into(), None, Command::Connect, Inbound::Transparent)
+            } else {
+                // 整个入站握手都要在 handshake 超时内完成
+                let deadline = Instant::now() + config.timeouts.handshake;
+                let handshake = async {
+                    // 不消耗数据，留给各自的握手去读
+                    let protocol = peek_protocol(&peer_left, deadline).await?;
+                    match protocol {
+                        Protocol::Socks5 => {
+                            let (dest, user, command) =
+                                socks5_accept(&mut peer_left, listener_config).await?;
+                            Ok((dest, user, command, Inbound::Socks5))
+                        }
+                        Protocol::Http => {
+                            let (request, user, pending) =
+                                http::accept(&mut peer_left, listener_config).await?;
+                            pending_data = pending;
+                            if !request.tunnel {
+                                http_body = Some(request.body);
+                            }
+                            let inbound = Inbound::Http {
+                                tunnel: request.tunnel,
+                            };
+                            Ok((request.dest, user, Command::Connect, inbound))
+                        }
+                        Protocol::Socks4 => {
+                            let dest = socks4_accept(&mut peer_left, listener_config).await?;
+                            Ok((dest, None, Command::Connect, Inbound::Socks4))
+                        }
+                        Protocol::Unknown => error_invalid_input(
+                            "Neither a NATed connection nor a known proxy protocol",
+                        ),
+                    }
+                };
+                match timeout_at(deadline, handshake).await {
+                    Ok(result) => result?,
+                    Err(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "inbound handshake timeout",
+                        ))
+                    }
                 }
-                _ => return error_invalid_input("Socksv5: unknown address type"),
             };
-            let port = peer_left.read_u16().await?;
-            peer_left.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
-            (addr, port).into()
-        };
         Ok(Client {
             // 上面的 dest 类型直到这里 dest 赋值给 Destination 类型的字段成员
             // dest 的类型才真正被确认，之前的 into 一直推导出 unknown
             dest,
-            config: config,
-            from_port: src_port,
+            config,
             left: peer_left,
             src: left_src,
-            pending_data: None,
+            pending_data,
+            http_body,
+            upstream: None,
+            listener,
+            user,
+            command,
+            inbound,
+            sniffed: None,
         })
     }
 }
 
+// socks5 握手，返回请求的目标、认证通过的用户名和 CMD
+async fn socks5_accept(
+    peer_left: &mut TcpStream,
+    listener: &Listener,
+) -> io::Result<(Destination, Option<String>, Command)> {
+    let user = socks5_authenticate(peer_left, listener).await?;
+    let request = match socks5::Request::read_from(peer_left).await {
+        Ok(request) => request,
+        Err(err) => {
+            // X'08' Address type not supported
+            if let Some(rep) = socks5::ReplyError::from_io_error(&err) {
+                peer_left
+                    .write_all(&socks5::Reply::error(rep).to_bytes()?)
+                    .await?;
+            }
+            return Err(err);
+        }
+    };
+    let command = match request.command {
+        socks5::CMD_CONNECT => Command::Connect,
+        socks5::CMD_UDP_ASSOCIATE => Command::UdpAssociate,
+        socks5::CMD_BIND => Command::Bind,
+        _ => {
+            // X'07' Command not supported
+            let reply = socks5::Reply::error(socks5::ReplyError::CommandNotSupported);
+            peer_left.write_all(&reply.to_bytes()?).await?;
+            return error_invalid_input("Socksv5, unsupported command");
+        }
+    };
+    // 回复都需要带上真实的地址
+    // CONNECT 在 connect_remote_server 之后回复，UDP ASSOCIATE 和 BIND 在各自的处理里回复
+    Ok((request.dest, user, command))
+}
+
+// socks4 没有密码，listener 要求认证时直接拒绝
+// USERID 只用来打日志
+async fn socks4_accept(peer_left: &mut TcpStream, listener: &Listener) -> io::Result<Destination> {
+    let request = socks4::Request::read_from(peer_left).await?;
+    let rejected = socks4::build_reply(socks4::REP_REJECTED, None);
+    if listener.requires_auth() {
+        peer_left.write_all(&rejected).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Socksv4, authentication is required but SOCKSv4 has no password",
+        ));
+    }
+    if request.command != socks4::CMD_CONNECT {
+        peer_left.write_all(&rejected).await?;
+        return error_invalid_input("Socksv4, unsupported command");
+    }
+    debug!(
+        "Socksv4, connect {} with userid {:?}",
+        request.dest, request.userid
+    );
+    Ok(request.dest)
+}
+
+// 协商认证方式，listener 配置了用户时要求 RFC 1929 用户名密码认证
+// 返回认证通过的用户名
+async fn socks5_authenticate(
+    peer_left: &mut TcpStream,
+    listener: &Listener,
+) -> io::Result<Option<String>> {
+    let methods = socks5::Greeting::read_from(peer_left).await?.methods;
+    if !listener.requires_auth() {
+        if !methods.contains(&socks5::METHOD_NO_AUTH) {
+            peer_left
+                .write_all(&[socks5::VERSION, socks5::METHOD_NOT_ACCEPTABLE])
+                .await?;
+            return error_invalid_input("Socksv5, client doesn't offer no auth");
+        }
+        peer_left
+            .write_all(&[socks5::VERSION, socks5::METHOD_NO_AUTH])
+            .await?;
+        return Ok(None);
+    }
+    if !methods.contains(&socks5::METHOD_USERNAME_PASSWORD) {
+        peer_left
+            .write_all(&[socks5::VERSION, socks5::METHOD_NOT_ACCEPTABLE])
+            .await?;
+        return error_invalid_input("Socksv5, username/password authentication is required");
+    }
+    peer_left
+        .write_all(&[socks5::VERSION, socks5::METHOD_USERNAME_PASSWORD])
+        .await?;
+    // https://tools.ietf.org/html/rfc1929
+    let ver = peer_left.read_u8().await?;
+    if ver != 0x01 {
+        return error_invalid_input("Socksv5, unknown username/password auth version");
+    }
+    let len = peer_left.read_u8().await? as usize;
+    let mut username = vec![0u8; len];
+    peer_left.read_exact(&mut username).await?;
+    let len = peer_left.read_u8().await? as usize;
+    let mut password = vec![0u8; len];
+    peer_left.read_exact(&mut password).await?;
+    let username = String::from_utf8_lossy(&username).into_owned();
+    if !listener.verify_user(&username, &password) {
+        peer_left.write_all(&[0x01, 0x01]).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Socksv5, authentication failed for user {}", username),
+        ));
+    }
+    peer_left.write_all(&[0x01, 0x00]).await?;
+    debug!("Socksv5, user {} authenticated", username);
+    Ok(Some(username))
+}
+
+impl Client {
+    pub fn listener(&self) -> &Listener {
+        &self.config.listeners[self.listener]
+    }
+}
+
+// 日志里用来标识一个连接：来源地址、listener 和用户
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}", self.src)?;
+        let listener = self.listener();
+        if !listener.name.is_empty() {
+            write!(f, " via {}", listener.name)?;
+        }
+        if let Some(ref user) = self.user {
+            write!(f, " user {}", user)?;
+        }
+        write!(f, "]")
+    }
+}
+
 impl Client {
     // REDIRECT 情况下不会有 socks 的握手流程
     // 起手流量是 TLS client hello
     // 需要我们从 TLS 嗅探出 domain name
     // 用于做 DNS 远程解析
     // 注意： 这里是 (self), 所以这函数会 consume 掉 Self
-    pub async fn retrive_dest(self) -> io::Result<Client> {
-        let Client {
-            mut left,
-            src,
-            mut dest,
-            from_port,
-            config,
-            pending_data,
-        } = self;
-        let wait = Duration::from_millis(500);
+    // 按 config.sniff 的顺序嗅探首包，认出来的协议记在 sniffed 里
+    pub async fn retrive_dest(mut self) -> io::Result<Client> {
+        // 从第一次读开始算，最多等 timeouts.sniff
+        let deadline = Instant::now() + self.config.timeouts.sniff;
         let mut buf = BytesMut::with_capacity(2048);
-        let mut pending_data = None;
-        buf.resize(buf.capacity(), 0);
-        if let Ok(len) = timeout(wait, left.read(&mut buf)).await? {
-            // 只保留读出的数据，其他的丢弃
-            // 这样保证往socket回写时不会写入初始化时的 0
-            buf.truncate(len);
-            match tls::parse_client_hello(&buf) {
-                Err(err) => info!("fail to parse hello: {}", err),
-                Ok(hello) => {
-                    if let Some(server_name) = hello.server_name {
-                        dest = (server_name.as_ref(), dest.port).into();
-                    }
+        let config = self.config.clone();
+        // client hello 可能分在多个 tcp 包里，判断不出来时继续读
+        // 超时说明 client 在等 server 先说话，或者数据不完整，都不修改 dest
+        let result = loop {
+            let len = match timeout_at(deadline, self.left.read_buf(&mut buf)).await {
+                Ok(len) => len?,
+                Err(_) => break SniffResult::Unknown,
+            };
+            match sniff::sniff(&buf, &config.sniff) {
+                SniffResult::Incomplete if len > 0 && buf.len() < sniff::MAX_SNIFF_LEN => {
+                    buf.reserve(2048);
                 }
+                result => break result,
             }
-            // 将从 socket 读取出的数据都存起来，后面发给server
-            // 通过tls parser 获取SNI只是为了remote dns
-            // 由于我们没有证书，无法做https代理
-            // 所以建立 tcp socket 后将从client读取的tls hello透明发给server
-            pending_data = Some(buf.freeze());
+        };
+        sniff::record_tls_stats(&result, &buf, &config.sniff);
+        match result {
+            SniffResult::Found(sniffed, sniffer) => {
+                debug!(
+                    "{} {} sniffed {} {:?}",
+                    self, self.dest, sniffed.protocol, sniffed.domain
+                );
+                if let (Some(ja3), Some(ja4)) = (&sniffed.ja3, &sniffed.ja4) {
+                    debug!(
+                        "{} {} alpn {:?} ja3 {} ja4 {}",
+                        self, self.dest, sniffed.alpn, ja3, ja4
+                    );
+                }
+                if let Some(ech) = &sniffed.ech {
+                    debug!(
+                        "{} {} ech {:?}, outer server name {:?}",
+                        self, self.dest, ech, sniffed.domain
+                    );
+                }
+                // 带 ECH 时按 routing.ech 决定要不要用 outer SNI
+                let use_domain = config.router.use_sniffed_domain(&sniffed);
+                if let (true, Some(domain)) = (sniffer.override_dest && use_domain, &sniffed.domain)
+                {
+                    self.dest = (domain.as_ref(), self.dest.port).into();
+                }
+                self.sniffed = Some(sniffed);
+            }
+            _ => debug!(
+                "{} {} unknown protocol after {} bytes",
+                self,
+                self.dest,
+                buf.len()
+            ),
         }
-        Ok(Client {
-            from_port,
-            dest,
-            left,
-            src,
-            pending_data,
-            config,
-        })
+        // 将从 socket 读取出的数据都存起来，后面发给server
+        // 通过tls parser 获取SNI只是为了remote dns
+        // 由于我们没有证书，无法做https代理
+        // 所以建立 tcp socket 后将从client读取的tls hello透明发给server
+        if !buf.is_empty() {
+            self.pending_data = Some(buf.freeze());
+        }
+        Ok(self)
+    }
+    // 按路由规则选 outbound，日志里记下命中的规则
+    // udp associate 和 bind 用请求里的 DST 匹配
+    fn route(&self) -> Outbound {
+        let session = Session {
+            dest: &self.dest,
+            src: self.src,
+            inbound: &self.listener().name,
+            user: self.user.as_deref(),
+            sniffed: self.sniffed.as_ref(),
+        };
+        route_session(&self.config, &session, self)
+    }
+    // 按路由规则选 outbound，结束后把结果回复给 client
+    pub async fn connect_remote_server(&mut self) -> io::Result<TcpStream> {
+        let result = match self.route() {
+            Outbound::Proxy => self.try_upstreams(upstream::candidates(&self.config)).await,
+            Outbound::Upstream(index) => self.try_upstreams(vec![index]).await,
+            Outbound::Direct => self.connect_direct().await,
+            Outbound::Reject => Err(socks5::ReplyError::NotAllowed.into()),
+        };
+        match result {
+            Ok((stream, bound)) => {
+                self.reply(Ok(bound)).await?;
+                Ok(stream)
+            }
+            Err(err) => {
+                // client 可能已经断开，回复失败不影响返回原来的错误
+                let _ = self.reply(Err(&err)).await;
+                Err(err)
+            }
+        }
+    }
+    // 按给出的顺序逐个尝试，tcp 连接或握手失败就换下一个
+    async fn try_upstreams(
+        &mut self,
+        candidates: Vec<usize>,
+    ) -> io::Result<(TcpStream, Destination)> {
+        let mut last_err = None;
+        for index in candidates {
+            let upstream = &self.config.upstreams[index];
+            match self.connect_upstream(upstream).await {
+                Ok(connected) => {
+                    debug!(
+                        "{} connect {} through upstream {}",
+                        self, self.dest, upstream
+                    );
+                    self.upstream = Some(UpstreamGuard::new(self.config.clone(), index));
+                    return Ok(connected);
+                }
+                Err(err) => {
+                    warn!(
+                        "{} upstream {} failed for {}: {}",
+                        self, upstream, self.dest, err
+                    );
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no upstream configured")))
+    }
+    // 直连时本地的地址就是 BND.ADDR
+    async fn connect_direct(&mut self) -> io::Result<(TcpStream, Destination)> {
+        let mut stream = direct::connect(&self.config, &self.dest).await?;
+        if let Some(ref data) = self.pending_data {
+            stream.write_all(data).await?;
+        }
+        debug!("{} connect {} directly", self, self.dest);
+        let bound = stream.local_addr()?.into();
+        Ok((stream, bound))
+    }
+    // 透明代理的 client 不需要回复
+    async fn reply(&mut self, result: Result<Destination, &io::Error>) -> io::Result<()> {
+        let buf = match (self.inbound, result) {
+            (Inbound::Transparent, _) => return Ok(()),
+            (Inbound::Socks5, Ok(bound)) => Reply::new(REP_SUCCEEDED, bound).to_bytes()?,
+            (Inbound::Socks5, Err(err)) => Reply::error(err.into()).to_bytes()?,
+            (Inbound::Socks4, Ok(bound)) => {
+                socks4::build_reply(socks4::REP_GRANTED, Some(&bound)).to_vec()
+            }
+            (Inbound::Socks4, Err(_)) => socks4::build_reply(socks4::REP_REJECTED, None).to_vec(),
+            (Inbound::Http { tunnel: true }, Ok(_)) => http::CONNECT_ESTABLISHED.to_vec(),
+            // 普通请求的响应由 server 发回来
+            (Inbound::Http { tunnel: false }, Ok(_)) => return Ok(()),
+            (Inbound::Http { .. }, Err(err)) => http::error_response(err),
+        };
+        self.left.write_all(&buf).await
+    }
+    // 返回 upstream 回复的 BND.ADDR
+    async fn connect_upstream(&self, upstream: &Upstream) -> io::Result<(TcpStream, Destination)> {
+        let Client {
+            ref dest, config, ..
+        } = self;
+        let mut stream =
+            match timeout(config.timeouts.connect, TcpStream::connect(upstream.addr)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    // 保留原来的 kind，回复 client 时用来选 REP
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!("connect upstream failed with error {}", err),
+                    ));
+                }
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "connect upstream timeout",
+                    ))
+                }
+            };
+        let handshake = async {
+            match upstream.protocol {
+                // we should handshake with socks5 server as the socks client
+                upstream::Protocol::Socks5 => {
+                    handshake(
+                        &mut stream,
+                        dest,
+                        self.pending_data.clone(),
+                        upstream.credentials(),
+                    )
+                    .await
+                }
+                // http 代理不会告诉我们它用的地址
+                upstream::Protocol::Http => {
+                    http::connect(
+                        &mut stream,
+                        dest,
+                        self.pending_data.clone(),
+                        upstream.credentials(),
+                    )
+                    .await?;
+                    Ok(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())
+                }
+            }
+        };
+        let bound = timeout(config.timeouts.handshake, handshake)
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} handshake timeout", upstream.protocol),
+                )
+            })??;
+        Ok((stream, bound))
     }
-    // connect to socks5 server
-    pub async fn connect_remote_server(&self) -> io::Result<TcpStream> {
+    // 控制连接关闭前一直中转 udp
+    // 每个 udp 包的目标也用同一个 session 匹配路由规则
+    pub async fn udp_associate(self) -> io::Result<()> {
+        let label = self.to_string();
+        let inbound = self.listener().name.clone();
         let Client {
-            ref dest,
-            ref from_port,
-            ref left,
+            left,
             config,
+            src,
+            dest,
+            user,
             ..
         } = self;
-        let socks_server = config.socks5_server;
-        let mut stream = match TcpStream::connect(socks_server).await {
-            Ok(stream) => stream,
-            Err(err) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionRefused,
-                    format!("connect remote socks server failed with error {}", err),
-                ))
-            }
+        let router_config = config.clone();
+        let route_label = label.clone();
+        let route = move |dest: &Destination| {
+            let session = Session {
+                dest,
+                src,
+                inbound: &inbound,
+                user: user.as_deref(),
+                sniffed: None,
+            };
+            route_session(&router_config, &session, &route_label)
         };
-        handshake(&mut stream, dest, self.pending_data.clone()).await?;
-        // we should handshake with socks5 server as the socks client
-        Ok(stream)
+        udp::associate(left, config, dest, route, label).await
+    }
+    // 两次回复之后 control 连接用来传数据
+    pub async fn bind(self) -> io::Result<()> {
+        let label = self.to_string();
+        let outbound = self.route();
+        bind::bind(self.left, self.config, self.dest, outbound, label).await
     }
     // use self, consume self
     pub async fn do_pipe(self, remote: TcpStream) -> io::Result<()> {
-        match pipe(self.left, remote).await {
+        let result = match self.http_body {
+            Some(body) => http::relay(self.left, remote, body).await,
+            None => pipe(self.left, remote).await,
+        };
+        match result {
             Ok(()) => Ok(()),
             Err(err) => Err(io::Error::new(
                 io::ErrorKind::BrokenPipe,
@@ -261,3 +642,169 @@ impl Client {
         }
     }
 }
+
+// 日志里记下命中的规则，label 标识是哪个连接
+fn route_session(config: &Config, session: &Session<'_>, label: impl fmt::Display) -> Outbound {
+    let (outbound, matched) = config.router.route(session);
+    let to: Cow<str> = match outbound {
+        Outbound::Proxy => "proxy".into(),
+        Outbound::Upstream(index) => format!("upstream {}", config.upstreams[index]).into(),
+        Outbound::Direct => "direct".into(),
+        Outbound::Reject => "reject".into(),
+    };
+    info!("{} {} matched {} -> {}", label, session.dest, matched, to);
+    outbound
+}
+
+// 连 upstream 或直连的错误按 kind 转成 socks5 的 REP
+#[tokio::test]
+async fn test_socks5_error_reply() {
+    use crate::protocols::socks5::ReplyError;
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    // 不需要认证的 greeting，然后 CONNECT example.com:443
+    peer.write_all(b"\x05\x01\x00").await.unwrap();
+    peer.write_all(b"\x05\x01\x00\x03\x0bexample.com\x01\xbb")
+        .await
+        .unwrap();
+    let config = Config {
+        listeners: vec![Listener::new(None, 0)],
+        ..Config::default()
+    };
+    let mut client = Client::from_socket(accepted, Arc::new(config), 0)
+        .await
+        .unwrap();
+    assert_eq!(client.inbound, Inbound::Socks5);
+    assert_eq!(client.dest, ("example.com", 443).into());
+    let mut method = [0u8; 2];
+    peer.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 0]);
+
+    let cases = vec![
+        (io::ErrorKind::ConnectionRefused.into(), 0x05),
+        (io::ErrorKind::TimedOut.into(), 0x06),
+        (io::Error::from(ReplyError::NotAllowed), 0x02),
+        (io::Error::other("other"), 0x01),
+    ];
+    for (err, code) in cases {
+        client.reply(Err(&err)).await.unwrap();
+        let err = socks5::read_reply(&mut peer).await.unwrap_err();
+        assert_eq!(ReplyError::from_io_error(&err).unwrap().code(), code);
+    }
+}
+
+// 发完 greeting 就不动的 client 也要在 handshake 超时内断开
+#[tokio::test]
+async fn test_inbound_handshake_timeout() {
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut config = Config {
+        listeners: vec![Listener::new(None, 0)],
+        ..Config::default()
+    };
+    config.timeouts.handshake = Duration::from_millis(100);
+    let config = Arc::new(config);
+    for greeting in [&b""[..], b"\x05\x01\x00", b"GET http://a/ HTTP/1.1\r\n"] {
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        peer.write_all(greeting).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let start = Instant::now();
+        let err = match Client::from_socket(accepted, config.clone(), 0).await {
+            Ok(_) => panic!("handshake should time out"),
+            Err(err) => err,
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut, "{:?}", greeting);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
+
+// udp associate 和 bind 也按请求里的 DST 匹配路由规则
+#[tokio::test]
+async fn test_route_udp_associate_and_bind() {
+    use crate::protocols::socks5::ReplyError;
+    use crate::router::Router;
+    use std::str::FromStr;
+    use tokio::net::TcpListener;
+
+    let mut config = Config::from_str(
+        r#"
+listeners:
+  - port: 1080
+upstreams:
+  - name: tcp-only
+    addr: 127.0.0.1:1
+routing:
+  rules:
+    - port: ["21"]
+      outbound: reject
+    - ip_cidr: [127.0.0.0/8]
+      outbound: tcp-only
+  final: direct
+"#,
+    )
+    .unwrap();
+    config.router = Router::new(&config).unwrap();
+    let config = Arc::new(config);
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let request = |command: u8, dest: SocketAddr| {
+        let config = config.clone();
+        let listener = &listener;
+        async move {
+            let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            peer.write_all(b"\x05\x01\x00").await.unwrap();
+            let mut buf = Vec::new();
+            socks5::Request {
+                command,
+                dest: dest.into(),
+            }
+            .encode(&mut buf)
+            .unwrap();
+            peer.write_all(&buf).await.unwrap();
+            let client = Client::from_socket(accepted, config, 0).await.unwrap();
+            let mut method = [0u8; 2];
+            peer.read_exact(&mut method).await.unwrap();
+            let task = tokio::spawn(async move {
+                match client.command {
+                    Command::Bind => client.bind().await,
+                    _ => client.udp_associate().await,
+                }
+            });
+            let reply = socks5::read_reply(&mut peer).await;
+            (reply, peer, task)
+        }
+    };
+    let rep = |reply: io::Result<Destination>| {
+        ReplyError::from_io_error(&reply.unwrap_err()).map(|err| err.code())
+    };
+
+    let ftp = SocketAddr::from((Ipv4Addr::LOCALHOST, 21));
+    let (reply, _, task) = request(socks5::CMD_BIND, ftp).await;
+    assert_eq!(rep(reply), Some(0x02));
+    assert!(task.await.unwrap().is_err());
+    let (reply, _, task) = request(socks5::CMD_UDP_ASSOCIATE, ftp).await;
+    assert_eq!(rep(reply), Some(0x02));
+    assert!(task.await.unwrap().is_err());
+    // 指定的 upstream 不支持 udp，不能退回直连
+    let dns = SocketAddr::from((Ipv4Addr::LOCALHOST, 53));
+    let (reply, _, task) = request(socks5::CMD_UDP_ASSOCIATE, dns).await;
+    assert_eq!(rep(reply), Some(0x07));
+    assert!(task.await.unwrap().is_err());
+    // 没有命中规则，final 是 direct
+    let any = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    let (reply, peer, task) = request(socks5::CMD_UDP_ASSOCIATE, any).await;
+    assert!(reply.is_ok());
+    drop(peer);
+    task.await.unwrap().unwrap();
+}
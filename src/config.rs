@@ -1,7 +1,584 @@
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    collections::HashMap,
+    fs,
+    hint::black_box,
+    io,
+    net::{IpAddr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::AtomicUsize,
+    time::Duration,
+};
 
+use serde::{Deserialize, Deserializer};
+
+pub use crate::upstream::{Policy, Upstream};
+use crate::{protocols::socks5, router::Router, sniff};
+
+pub const OUTBOUND_PROXY: &str = "proxy";
+pub const OUTBOUND_DIRECT: &str = "direct";
+pub const OUTBOUND_REJECT: &str = "reject";
+pub const ECH_SNI: &str = "sni";
+pub const ECH_IP: &str = "ip";
+
+// 配置文件的结构，参考 config.example.yaml
+// 命令行参数会覆盖文件里的值
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    pub socks5_server: SocketAddr,
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
+    #[serde(default)]
+    pub upstreams: Vec<Upstream>,
+    #[serde(default)]
+    pub upstream_policy: Policy,
+    // round-robin 的游标
+    #[serde(skip)]
+    pub(crate) next_upstream: AtomicUsize,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub routing: Routing,
+    #[serde(default)]
+    pub direct: Direct,
+    // 透明代理的连接按首包内容嗅探协议，按顺序尝试，为空时不嗅探
+    #[serde(default = "default_sniff")]
+    pub sniff: Vec<SniffConfig>,
+    // 由 routing 编译出来，见 Router::new
+    #[serde(skip)]
+    pub router: Router,
+    #[serde(default)]
+    pub log: Log,
+    // 同时处理的连接数上限
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    // 给路由规则和日志用
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "default_host")]
     pub host: IpAddr,
-    pub port: usize,
+    pub port: u16,
+    // socks5 入站的 RFC 1929 用户和 http 入站的 Basic 认证，username -> password
+    // 为空时不需要认证
+    #[serde(default)]
+    pub users: HashMap<String, String>,
+    // 每行一个 username:password，# 开头为注释
+    // 和 users 合并
+    pub users_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    // 和上游建立 tcp 连接
+    #[serde(default = "default_connect_timeout", deserialize_with = "millis")]
+    pub connect: Duration,
+    // 和 client、上游的代理协议握手，client 连上之后一直不发数据也按这个超时
+    #[serde(default = "default_handshake_timeout", deserialize_with = "millis")]
+    pub handshake: Duration,
+    // 嗅探时等待 client 数据的总时间，client hello 分在多个包里时会一直读到超时
+    #[serde(default = "default_sniff_timeout", deserialize_with = "millis")]
+    pub sniff: Duration,
+    // BIND 等待对端连进来
+    #[serde(default = "default_bind_timeout", deserialize_with = "millis")]
+    pub bind: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Routing {
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    // 从文件加载的列表，名字 -> 配置，规则里用 provider 引用
+    #[serde(default)]
+    pub providers: HashMap<String, ProviderConfig>,
+    // v2ray 格式的数据文件，规则的 provider 里用 geoip:cn、geosite:cn 引用
+    pub geoip: Option<PathBuf>,
+    pub geosite: Option<PathBuf>,
+    // 带 ECH 的 tls 连接，嗅探出的域名是 outer 的 public name，只对 ech_public_names 生效
+    // sni 照常使用，ip 不使用域名，按原始的目标 ip 路由，其他值当作 outbound 直接使用
+    #[serde(default = "default_ech")]
+    pub ech: String,
+    // outer SNI 是这些域名（含子域名）时才算真的 ECH，其他的是 GREASE ECH，SNI 就是真实域名
+    #[serde(default = "default_ech_public_names")]
+    pub ech_public_names: Vec<String>,
+    // 没有规则命中时使用的 outbound
+    // proxy 走 upstreams，direct 直连，reject 拒绝，或者某个 upstream 的名字
+    #[serde(rename = "final", default = "default_outbound")]
+    pub final_outbound: String,
+}
+
+// 一条路由规则，按顺序匹配，第一条命中的生效
+// 同一字段内任意一项命中即可
+// domain*、ip_cidr、provider 都是描述目标地址的，之间任意一项命中即可，其他字段需要同时命中
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    #[serde(default)]
+    pub domain: Vec<String>,
+    #[serde(default)]
+    pub domain_suffix: Vec<String>,
+    #[serde(default)]
+    pub domain_keyword: Vec<String>,
+    #[serde(default)]
+    pub domain_regex: Vec<String>,
+    #[serde(default)]
+    pub ip_cidr: Vec<String>,
+    // routing.providers 里的名字，或者 geoip:cn、geosite:cn
+    #[serde(default)]
+    pub provider: Vec<String>,
+    // "443" 或者 "8000-9000"
+    #[serde(default)]
+    pub port: Vec<String>,
+    #[serde(default)]
+    pub src_cidr: Vec<String>,
+    #[serde(default)]
+    pub inbound: Vec<String>,
+    // listener 认证过的用户名，没有认证的连接不命中
+    #[serde(default)]
+    pub user: Vec<String>,
+    // 嗅探出的协议，只有透明代理的连接会嗅探
+    #[serde(default)]
+    pub protocol: Vec<sniff::Protocol>,
+    // 下面三个来自 tls client hello，client 提供的 ALPN 里任意一个命中即可
+    // alpn 区分大小写，ja3、ja4 不区分
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    #[serde(default)]
+    pub ja3: Vec<String>,
+    #[serde(default)]
+    pub ja4: Vec<String>,
+    pub outbound: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderConfig {
+    #[serde(rename = "type")]
+    pub provider_type: ProviderType,
+    // 每行一条，# 开头为注释
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderType {
+    // 1.2.3.0/24、2001:db8::/32
+    IpCidr,
+    // example.com 匹配它和子域名，full:example.com 只匹配它本身
+    Domain,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniffConfig {
+    pub protocol: sniff::Protocol,
+    // 用取出的域名替换目标地址，这样 upstream 用域名连接，走远程 dns
+    // 为 false 时域名只用来匹配路由规则
+    #[serde(rename = "override", default = "default_true")]
+    pub override_dest: bool,
+}
+
+// 直连的 outbound
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Direct {
+    // 透明代理时给直连的 socket 设置 SO_MARK，配合 iptables 避免回环
+    pub mark: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Log {
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    // 定期输出 tls 嗅探的统计，为 0 时不输出
+    #[serde(default = "default_stats_interval", deserialize_with = "millis")]
+    pub stats_interval: Duration,
+}
+
+fn default_max_connections() -> usize {
+    1024
+}
+
+fn default_host() -> IpAddr {
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+}
+
+fn default_connect_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_handshake_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_sniff_timeout() -> Duration {
+    Duration::from_millis(500)
+}
+
+fn default_bind_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_sniff() -> Vec<SniffConfig> {
+    [sniff::Protocol::Tls, sniff::Protocol::Http]
+        .iter()
+        .map(|&protocol| SniffConfig {
+            protocol,
+            override_dest: true,
+        })
+        .collect()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_ech() -> String {
+    String::from(ECH_SNI)
+}
+
+// cloudflare 所有开了 ECH 的站点共用这个 public name
+fn default_ech_public_names() -> Vec<String> {
+    vec![String::from("cloudflare-ech.com")]
+}
+
+fn default_outbound() -> String {
+    String::from(OUTBOUND_PROXY)
+}
+
+fn default_log_level() -> String {
+    String::from("info")
+}
+
+fn default_stats_interval() -> Duration {
+    Duration::from_secs(600)
+}
+
+// 配置文件里的时间统一用毫秒
+fn millis<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+fn invalid_config<T>(msg: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listeners: Vec::new(),
+            upstreams: Vec::new(),
+            upstream_policy: Policy::default(),
+            next_upstream: AtomicUsize::new(0),
+            timeouts: Timeouts::default(),
+            routing: Routing::default(),
+            direct: Direct::default(),
+            sniff: default_sniff(),
+            router: Router::default(),
+            log: Log::default(),
+            max_connections: default_max_connections(),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: default_connect_timeout(),
+            handshake: default_handshake_timeout(),
+            sniff: default_sniff_timeout(),
+            bind: default_bind_timeout(),
+        }
+    }
+}
+
+impl Default for Routing {
+    fn default() -> Self {
+        Routing {
+            rules: Vec::new(),
+            providers: HashMap::new(),
+            geoip: None,
+            geosite: None,
+            ech: default_ech(),
+            ech_public_names: default_ech_public_names(),
+            final_outbound: default_outbound(),
+        }
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            level: default_log_level(),
+            stats_interval: default_stats_interval(),
+        }
+    }
+}
+
+impl Listener {
+    pub fn new(host: Option<IpAddr>, port: u16) -> Self {
+        Listener {
+            name: String::new(),
+            host: host.unwrap_or_else(default_host),
+            port,
+            users: HashMap::new(),
+            users_file: None,
+        }
+    }
+    pub fn requires_auth(&self) -> bool {
+        !self.users.is_empty()
+    }
+    // 不按 username 查表，每个用户都比较一遍，耗时不能透露用户名是否存在
+    pub fn verify_user(&self, username: &str, password: &[u8]) -> bool {
+        let mut matched = false;
+        for (name, expected) in self.users.iter() {
+            // 用 & 和 | 而不是 && 和 ||，不短路
+            matched |= constant_time_eq(name.as_bytes(), username.as_bytes())
+                & constant_time_eq(expected.as_bytes(), password);
+        }
+        matched
+    }
+    fn load_users_file(&mut self) -> io::Result<()> {
+        let path = match self.users_file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let content = fs::read_to_string(path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("failed to read users file {}: {}", path.display(), err),
+            )
+        })?;
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((username, password)) if !username.is_empty() => {
+                    self.users
+                        .insert(String::from(username), String::from(password));
+                }
+                _ => {
+                    return invalid_config(format!(
+                        "{}: expect username:password at line {}",
+                        path.display(),
+                        i + 1
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// 不在第一个不同的字节处提前返回，长度不同也比较完 b，避免按耗时猜出内容或长度
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let diff = b
+        .iter()
+        .enumerate()
+        .fold((a.len() != b.len()) as u8, |diff, (i, y)| {
+            black_box(diff | (a.get(i).copied().unwrap_or(0) ^ y))
+        });
+    diff == 0
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("failed to read config file {}: {}", path.display(), err),
+            )
+        })?;
+        let mut config = content
+            .parse::<Config>()
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+        for listener in config.listeners.iter_mut() {
+            listener.load_users_file()?;
+        }
+        Ok(config)
+    }
+
+    // 合并完命令行参数后再检查
+    pub fn validate(&self) -> io::Result<()> {
+        if self.listeners.is_empty() {
+            return invalid_config(String::from(
+                "no listener configured, use --port or add one to the config file",
+            ));
+        }
+        for listener in self.listeners.iter() {
+            for (username, password) in listener.users.iter() {
+                socks5::validate_credentials(username, password).or_else(|err| {
+                    invalid_config(format!(
+                        "listener {}: user {}: {}",
+                        listener.port, username, err
+                    ))
+                })?;
+            }
+        }
+        for (i, upstream) in self.upstreams.iter().enumerate() {
+            upstream.validate().or_else(invalid_config)?;
+            // 名字用在路由规则的 outbound 里
+            if upstream.name.is_empty() {
+                continue;
+            }
+            // sni 和 ip 是 routing.ech 的取值，不能当作 outbound 的名字
+            let reserved = [
+                OUTBOUND_PROXY,
+                OUTBOUND_DIRECT,
+                OUTBOUND_REJECT,
+                ECH_SNI,
+                ECH_IP,
+            ];
+            if reserved.contains(&upstream.name.as_str()) {
+                return invalid_config(format!(
+                    "upstream name {} is reserved for outbound or routing.ech",
+                    upstream.name
+                ));
+            }
+            if self.upstreams[..i].iter().any(|u| u.name == upstream.name) {
+                return invalid_config(format!("duplicate upstream name {}", upstream.name));
+            }
+        }
+        if self.max_connections == 0 {
+            return invalid_config(String::from("max_connections must be greater than 0"));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Config {
+    type Err = io::Error;
+    // serde_yaml 的错误信息里带有行号和列号
+    fn from_str(content: &str) -> io::Result<Config> {
+        match serde_yaml::from_str(content) {
+            Ok(config) => Ok(config),
+            Err(err) => invalid_config(format!("invalid config, {}", err)),
+        }
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    let config = Config::from_str(
+        r#"
+listeners:
+  - name: lan
+    host: 0.0.0.0
+    port: 1080
+    users:
+      alice: secret
+  - port: 9999
+upstreams:
+  - addr: 127.0.0.1:1081
+  - name: backup
+    addr: "[::1]:1081"
+upstream_policy: least-connections
+timeouts:
+  sniff: 300
+routing:
+  rules:
+    - domain_suffix: [example.com]
+      outbound: direct
+  final: proxy
+log:
+  level: debug
+"#,
+    )
+    .unwrap();
+    assert_eq!(config.listeners.len(), 2);
+    assert_eq!(config.listeners[0].name, "lan");
+    assert_eq!(config.listeners[1].host, default_host());
+    assert!(config.listeners[0].verify_user("alice", b"secret"));
+    assert!(!config.listeners[0].verify_user("alice", b"wrong"));
+    assert!(!config.listeners[1].requires_auth());
+    assert_eq!(config.upstreams[0].addr, "127.0.0.1:1081".parse().unwrap());
+    assert_eq!(config.upstreams[1].name, "backup");
+    assert_eq!(config.upstream_policy, Policy::LeastConnections);
+    assert_eq!(config.timeouts.sniff, Duration::from_millis(300));
+    assert_eq!(config.timeouts.connect, default_connect_timeout());
+    assert_eq!(config.routing.rules[0].domain_suffix, ["example.com"]);
+    assert_eq!(config.log.level, "debug");
+    assert_eq!(config.max_connections, default_max_connections());
+    assert!(config.validate().is_ok());
+    assert!(!config.listeners[0].verify_user("alice", b"secre"));
+    assert!(!config.listeners[0].verify_user("bob", b"secret"));
+    assert!(!config.listeners[0].verify_user("alic", b"secret"));
+    assert!(!config.listeners[0].verify_user("alice", b"secret\0"));
+    assert!(!config.listeners[0].verify_user("", b""));
+    assert!(constant_time_eq(b"", b""));
+    assert!(!constant_time_eq(b"a", b""));
+    assert!(!constant_time_eq(b"", b"a"));
+
+    // 和 upstream 一样，密码不能为空
+    let mut config = config;
+    config.listeners[0]
+        .users
+        .insert(String::from("bob"), String::new());
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("password"), "{}", err);
+}
+
+// 仓库里的示例配置要能直接用
+#[test]
+fn test_parse_example_config() {
+    let config = Config::from_str(include_str!("../config.example.yaml")).unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.listeners[0].port, 9999);
+    assert_eq!(config.upstreams.len(), 2);
+    assert_eq!(config.direct.mark, None);
+    crate::router::Router::new(&config).unwrap();
+}
+
+#[test]
+fn test_reserved_upstream_name() {
+    for name in ["proxy", "direct", "reject", "sni", "ip"] {
+        let config = Config::from_str(&format!(
+            "listeners:\n  - port: 1080\nupstreams:\n  - name: {}\n    addr: 127.0.0.1:1080\n",
+            name
+        ))
+        .unwrap();
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("reserved"), "{}", err);
+    }
+    let config = Config::from_str(
+        "listeners:\n  - port: 1080\nupstreams:\n  - name: sni-exit\n    addr: 127.0.0.1:1080\n",
+    )
+    .unwrap();
+    config.validate().unwrap();
+}
+
+#[test]
+fn test_config_error_has_line_number() {
+    let err = Config::from_str("listeners:\n  - port: 1080\n    hots: 0.0.0.0\n").unwrap_err();
+    assert!(err.to_string().contains("line 3"), "{}", err);
+    let err = Config::from_str("upstreams:\n  - addr: not-an-address\n").unwrap_err();
+    assert!(err.to_string().contains("line 2"), "{}", err);
+}
+
+#[test]
+fn test_load_users_file() {
+    let path = std::env::temp_dir().join(format!("ooproxy-users-{}", std::process::id()));
+    fs::write(&path, "# comment\n\nbob:pass:word\n carol:x \n").unwrap();
+    let mut listener = Listener::new(None, 1080);
+    listener.users_file = Some(path.clone());
+    listener.load_users_file().unwrap();
+    assert!(listener.verify_user("bob", b"pass:word"));
+    assert!(listener.verify_user("carol", b"x"));
+    fs::write(&path, "bob:pass\nnobody\n").unwrap();
+    let err = listener.load_users_file().unwrap_err();
+    assert!(err.to_string().contains("line 2"), "{}", err);
+    fs::remove_file(&path).unwrap();
 }
@@ -0,0 +1,141 @@
+use std::{io, net::SocketAddr};
+
+use tokio::{
+    net::{lookup_host, TcpSocket, TcpStream},
+    time::timeout,
+};
+
+use crate::{
+    client::{Address, Destination},
+    config::Config,
+    linux::set_mark,
+};
+
+// 不经过 upstream，直接连目标
+// 域名在本地解析，按解析结果的顺序逐个尝试
+pub async fn connect(config: &Config, dest: &Destination) -> io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = match dest.host {
+        Address::Ip(ip) => vec![SocketAddr::new(ip, dest.port)],
+        Address::Domain(ref domain) => lookup_host((domain.as_ref(), dest.port))
+            .await
+            .map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::HostUnreachable,
+                    format!("failed to resolve {}: {}", domain, err),
+                )
+            })?
+            .collect(),
+    };
+    let mut last_err = None;
+    for addr in addrs {
+        match timeout(
+            config.timeouts.connect,
+            connect_addr(addr, config.direct.mark),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => last_err = Some(err),
+            Err(_) => {
+                last_err = Some(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("connect {} timeout", addr),
+                ))
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::HostUnreachable,
+            format!("{} has no address", dest.host),
+        )
+    }))
+}
+
+async fn connect_addr(addr: SocketAddr, mark: Option<u32>) -> io::Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(mark) = mark {
+        set_mark(&socket, mark)?;
+    }
+    socket.connect(addr).await
+}
+
+#[tokio::test]
+async fn test_connect() {
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = Config::default();
+    let stream = connect(&config, &addr.into()).await.unwrap();
+    let (_, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer, stream.local_addr().unwrap());
+    // localhost 可能先解析出 ::1，连不上时要接着试 127.0.0.1
+    let stream = connect(&config, &("localhost", addr.port()).into())
+        .await
+        .unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), addr);
+    // 端口没人监听
+    drop(listener);
+    let err = connect(&config, &addr.into()).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+// 超时是每个地址单独算的，超时后报 TimedOut 而不是一直等
+#[tokio::test]
+async fn test_connect_timeout() {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    // backlog 为 0，accept 队列里有一个连接之后新的 SYN 会被丢掉
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+    let listener = socket.listen(0).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut config = Config::default();
+    config.timeouts.connect = Duration::from_millis(200);
+    let mut queued = Vec::new();
+    let err = loop {
+        match connect(&config, &addr.into()).await {
+            Ok(stream) => queued.push(stream),
+            Err(err) => break err,
+        }
+        assert!(queued.len() < 8, "listen backlog is not enforced");
+    };
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(err.to_string(), format!("connect {} timeout", addr));
+}
+
+// 打上 SO_MARK 需要 CAP_NET_ADMIN
+#[tokio::test]
+async fn test_connect_mark() {
+    use std::{mem, net::Ipv4Addr, os::unix::io::AsRawFd};
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut config = Config::default();
+    config.direct.mark = Some(255);
+    let stream = match connect(&config, &listener.local_addr().unwrap().into()).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            return;
+        }
+    };
+    let mut mark = 0u32;
+    let mut len = mem::size_of::<u32>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mut mark as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    assert_eq!(res, 0);
+    assert_eq!(mark, 255);
+}
@@ -1,8 +1,15 @@
+pub mod bind;
 pub mod client;
 pub mod config;
+pub mod direct;
 pub mod linux;
 pub mod protocols;
+pub mod quic;
+pub mod router;
+pub mod sniff;
 pub mod stream;
 pub mod tls;
+pub mod udp;
+pub mod upstream;
 mod utils;
-pub use self::utils::copy_from_to;
\ No newline at end of file
+pub use self::utils::copy_from_to;
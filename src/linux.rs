@@ -1,4 +1,3 @@
-use std::io::ErrorKind;
 use std::net::SocketAddrV4;
 use std::os::unix::prelude::AsRawFd;
 use std::{io, mem, net::SocketAddrV6};
@@ -13,7 +12,7 @@ where
 {
     let addr = getsockopt(fd.as_raw_fd(), OriginalDst).map_err(|e| match e {
         nix::Error::Sys(err) => io::Error::from(err),
-        _ => io::Error::new(ErrorKind::Other, e),
+        _ => io::Error::other(e),
     })?;
     let addr = SocketAddrV4::new(
         u32::from_be(addr.sin_addr.s_addr).into(),
@@ -46,7 +45,7 @@ where
     if res != 0 {
         // 出错
         // C 常用非0作为错误返回
-        return Err(io::Error::new(ErrorKind::Other, "getsockopt fail"));
+        return Err(io::Error::other("getsockopt fail"));
     }
     // https://tools.ietf.org/html/rfc2553#section-3.3
     // 看起来 IPv6 对 socket有扩展，并不和v4一样
@@ -58,3 +57,23 @@ where
     );
     Ok(addr)
 }
+
+// SO_MARK，直连的流量打上标记，iptables 里跳过这个标记避免又被 REDIRECT 回来
+pub fn set_mark<F>(fd: &F, mark: u32) -> io::Result<()>
+where
+    F: AsRawFd,
+{
+    let res = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const _ as *const c_void,
+            mem::size_of::<u32>() as socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
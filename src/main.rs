@@ -6,27 +6,55 @@ use std::{
         Write,
     },
     net::{IpAddr, SocketAddr},
+    process,
+    str::FromStr,
     sync::Arc,
-    u16,
+    time::Duration,
 };
 
-use clap::{load_yaml, App, AppSettings};
-use ooproxy::{client::Client, config::Config, stream::{BiPipe, StreamWithBuffer}};
-use tokio::net::{TcpListener, TcpStream};
+use clap::{load_yaml, AppSettings, ArgMatches};
+use ooproxy::{
+    client::{Client, Command, Inbound},
+    config::{Config, Listener, Upstream},
+    router::Router,
+    tls,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    time::{interval, sleep},
+};
 
-use log::{error, info, warn, LevelFilter};
+use log::{debug, error, info, warn, LevelFilter};
+
+// accept 出错（比如 EMFILE）时等一会再继续，避免空转
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
 #[tokio::main]
 async fn main() {
     let yaml = load_yaml!("./cli.yaml");
-    let app = clap::App::from_yaml(&yaml)
+    let app = clap::App::from_yaml(yaml)
         .setting(AppSettings::ColoredHelp)
         .setting(AppSettings::UnifiedHelpMessage)
         .get_matches();
+    // logger 还没初始化，配置出错只能直接打到 stderr
+    let mut config = match load_config(&app) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    let log_level: LevelFilter = match config.log.level.parse() {
+        Ok(level) => level,
+        Err(_) => {
+            eprintln!("unknown log level {}", config.log.level);
+            process::exit(1);
+        }
+    };
     // enable log!
     let mut logger = env_logger::Builder::new();
-    let log_level: &str = app.value_of("log-level").expect("we need log level");
     logger
-        .filter(None, log_level.parse().expect("unknown log level"))
+        .filter(None, log_level)
         .filter_module("tokio_net", LevelFilter::Warn)
         .target(env_logger::Target::Stdout)
         .format(|buf, r| {
@@ -41,50 +69,165 @@ async fn main() {
         })
         .init();
     // info! 等需要放到 logger 之后，否则不会输出
-    // info!("111");
-    let host: IpAddr = app
-        .value_of("host")
-        .expect("missing host")
-        .parse()
-        .expect("invalid address");
+    // 加载 provider 时会打日志，所以路由规则放到这里编译
+    config.router = match Router::new(&config) {
+        Ok(router) => router,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
+    let config = Arc::new(config);
+    if !config.sniff.is_empty() && config.log.stats_interval > Duration::ZERO {
+        tokio::spawn(log_sniff_stats(config.log.stats_interval));
+    }
+    // 每个连接占一个 permit，连接结束时 permit 随 task drop 归还
+    // 所有 listener 共享同一个上限
+    let limiter = Arc::new(Semaphore::new(config.max_connections));
+    let mut servers = Vec::with_capacity(config.listeners.len());
+    for (index, listener) in config.listeners.iter().enumerate() {
+        // start listening
+        let addr = SocketAddr::new(listener.host, listener.port);
+        let listener = match TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("failed to bind {}: {}", addr, err);
+                process::exit(1);
+            }
+        };
+        info!("listen on {}", addr);
+        servers.push(tokio::spawn(serve(
+            listener,
+            index,
+            config.clone(),
+            limiter.clone(),
+        )));
+    }
+    for server in servers {
+        if let Err(err) = server.await {
+            error!("listener stopped with error {}", err);
+        }
+    }
+}
+
+// 读取配置文件，再用命令行参数覆盖
+fn load_config(app: &ArgMatches) -> io::Result<Config> {
+    let mut config = match app.value_of("config") {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    let host: Option<IpAddr> = parse_arg(app, "host")?;
+    let port: Option<u16> = parse_arg(app, "port")?;
+    // --host/--port 覆盖第一个 listener
+    match (config.listeners.first_mut(), port) {
+        (Some(listener), _) => {
+            if let Some(host) = host {
+                listener.host = host;
+            }
+            if let Some(port) = port {
+                listener.port = port;
+            }
+        }
+        (None, Some(port)) => config.listeners.push(Listener::new(host, port)),
+        (None, None) => (),
+    }
+    if let Some(addr) = parse_arg::<SocketAddr>(app, "socks5")? {
+        config.upstreams = vec![Upstream::new(addr)];
+    }
+    if let Some(max_connections) = parse_arg(app, "max-connections")? {
+        config.max_connections = max_connections;
+    }
+    if let Some(level) = app.value_of("log-level") {
+        config.log.level = String::from(level);
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+fn parse_arg<T: FromStr>(app: &ArgMatches, name: &str) -> io::Result<Option<T>> {
+    match app.value_of(name) {
+        None => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid value {} for --{}", value, name),
+            )
+        }),
+    }
+}
+
+async fn serve(listener: TcpListener, index: usize, config: Arc<Config>, limiter: Arc<Semaphore>) {
+    loop {
+        let permit = match limiter.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                // 达到上限后不再 accept，新连接留在内核的 backlog 里
+                // 直到有连接结束
+                warn!(
+                    "reach max connections {}, waiting for a free slot",
+                    config.max_connections
+                );
+                limiter
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("connection limiter closed")
+            }
+        };
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // 单个 accept 失败不能让整个 proxy 退出
+                error!("accept error {}", err);
+                sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let config = config.clone();
+        tokio::spawn(async move {
+            debug!("accept connection from {}", peer);
+            if let Err(err) = handle_client(socket, config, index).await {
+                error!("handle_client error {}", err);
+            }
+            drop(permit);
+        });
+    }
+}
 
-    let port: usize = app
-        .value_of("port")
-        .expect("missing port")
-        .parse()
-        .expect("invalid port number");
-    let socks_proxy_server: SocketAddr = app
-        .value_of("socks5")
-        .expect("socks5 server address missing")
-        .parse()
-        .expect("invalid socket address");
-    let config = Arc::new(Config {
-        socks5_server: socks_proxy_server,
-        host,
-        port,
-    });
-    // start listening
-    let mut addr = SocketAddr::new(host, port as u16);
-    let listener = TcpListener::bind(&addr).await.expect("Failed to bind port");
-    info!("listen on {}", addr);
-    while let Ok((socks, addr)) = listener.accept().await {
-        let result = handle_client(socks, config.clone()).await;
-        if let Err(err) = result {
-            error!("handle_client error {}", err);
+// 定期输出嗅探 client hello 的结果计数，没有新的连接时不输出
+async fn log_sniff_stats(period: Duration) {
+    let mut ticker = interval(period);
+    // 第一次 tick 立即返回
+    ticker.tick().await;
+    let mut last = 0;
+    loop {
+        ticker.tick().await;
+        let total = tls::STATS.total();
+        if total != last {
+            info!("tls sniff stats: {}", tls::STATS);
+            last = total;
         }
     }
 }
 
-async fn handle_client(peer_left: TcpStream, config: Arc<Config>) -> io::Result<()> {
-    let mut client = Client::from_socket(peer_left, config).await?;
-    let remote = if client.dest.port == 443 {
-        // https
-        // try parse server name from TLS server_name extension
+async fn handle_client(
+    peer_left: TcpStream,
+    config: Arc<Config>,
+    listener: usize,
+) -> io::Result<()> {
+    let sniff = !config.sniff.is_empty();
+    let mut client = Client::from_socket(peer_left, config, listener).await?;
+    match client.command {
+        Command::UdpAssociate => return client.udp_associate().await,
+        Command::Bind => return client.bind().await,
+        Command::Connect => (),
+    }
+    // socks5 client 要等到回复之后才会发数据，只能嗅探透明代理的连接
+    // 按内容判断协议，不看端口
+    if sniff && client.inbound == Inbound::Transparent {
         client = client.retrive_dest().await?;
-        client.connect_remote_server().await?
-    } else {
-        client.connect_remote_server().await?
-    };
+    }
+    let remote = client.connect_remote_server().await?;
     client.do_pipe(remote).await?;
     Ok(())
 }
@@ -0,0 +1,136 @@
+// 同一个端口上区分入站协议，只看开头几个字节
+use std::io;
+
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout_at, Duration, Instant},
+};
+
+// 判断协议最多需要的字节数，"OPTIONS " 最长
+const PEEK_SIZE: usize = 8;
+// 数据不够时隔一会再 peek
+const PEEK_INTERVAL: Duration = Duration::from_millis(5);
+
+// 请求方法后面跟一个空格
+// https://tools.ietf.org/html/rfc7231#section-4.1
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"CONNECT ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Socks5,
+    Socks4,
+    Http,
+    Unknown,
+}
+
+// 数据不够判断时返回 None
+pub fn detect(buf: &[u8]) -> Option<Protocol> {
+    let first = *buf.first()?;
+    let protocol = match first {
+        // VER NMETHODS，至少有一个 method
+        0x05 => match buf.get(1)? {
+            0 => Protocol::Unknown,
+            _ => Protocol::Socks5,
+        },
+        // VN CD，CD 只有 CONNECT 和 BIND
+        0x04 => match buf.get(1)? {
+            0x01 | 0x02 => Protocol::Socks4,
+            _ => Protocol::Unknown,
+        },
+        b'A'..=b'Z' => {
+            let mut incomplete = false;
+            for method in HTTP_METHODS {
+                let len = buf.len().min(method.len());
+                if buf[..len] == method[..len] {
+                    if len == method.len() {
+                        return Some(Protocol::Http);
+                    }
+                    incomplete = true;
+                }
+            }
+            if incomplete {
+                return None;
+            }
+            Protocol::Unknown
+        }
+        _ => Protocol::Unknown,
+    };
+    Some(protocol)
+}
+
+// peek 不会消耗数据，判断完后由各自的握手从头读
+// 到 deadline 还判断不出来就当作 Unknown，一个字节都没收到时返回 TimedOut
+pub async fn peek_protocol(stream: &TcpStream, deadline: Instant) -> io::Result<Protocol> {
+    let mut buf = [0u8; PEEK_SIZE];
+    loop {
+        // 没有数据时 peek 会一直等，不能让空闲的 client 一直占着连接数
+        let n = match timeout_at(deadline, stream.peek(&mut buf)).await {
+            Ok(n) => n?,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no data before handshake timeout",
+                ))
+            }
+        };
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before any data",
+            ));
+        }
+        if let Some(protocol) = detect(&buf[..n]) {
+            return Ok(protocol);
+        }
+        if n == PEEK_SIZE || Instant::now() >= deadline {
+            return Ok(Protocol::Unknown);
+        }
+        // 有数据时 peek 会立刻返回，等一会再看有没有更多数据
+        sleep(PEEK_INTERVAL).await;
+    }
+}
+
+#[test]
+fn test_detect() {
+    assert_eq!(detect(&[]), None);
+    assert_eq!(detect(&[5]), None);
+    assert_eq!(detect(&[5, 1, 0]), Some(Protocol::Socks5));
+    assert_eq!(detect(&[5, 0]), Some(Protocol::Unknown));
+    assert_eq!(detect(&[4, 1, 0, 80]), Some(Protocol::Socks4));
+    assert_eq!(detect(&[4, 3]), Some(Protocol::Unknown));
+    assert_eq!(detect(b"GET / HTTP/1.1"), Some(Protocol::Http));
+    assert_eq!(detect(b"CONNECT example.com:443"), Some(Protocol::Http));
+    // 可能是 CONNECT 也可能是别的
+    assert_eq!(detect(b"CONN"), None);
+    assert_eq!(detect(b"P"), None);
+    assert_eq!(detect(b"GETX"), Some(Protocol::Unknown));
+    assert_eq!(detect(b"SSH-2.0-OpenSSH"), Some(Protocol::Unknown));
+    // tls client hello
+    assert_eq!(detect(&[0x16, 0x03, 0x01]), Some(Protocol::Unknown));
+}
+
+#[tokio::test]
+async fn test_peek_idle() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let wait = Duration::from_millis(100);
+    let start = Instant::now();
+    let err = peek_protocol(&stream, start + wait).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < wait * 5);
+}
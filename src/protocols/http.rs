@@ -0,0 +1,750 @@
+// http 代理入站
+// CONNECT host:port 建立隧道，absolute-URI 的普通请求改写成 origin-form 后转发
+// 普通请求每个连接只转发第一个，后面 pipeline 的请求丢掉，响应完就关闭连接
+// https://tools.ietf.org/html/rfc7231#section-4.3.6
+use std::{
+    io::{self, ErrorKind},
+    net::IpAddr,
+};
+
+use bytes::{Bytes, BytesMut};
+use log::debug;
+use tokio::{
+    io::{self as tokio_io, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    client::{Address, Destination},
+    config::Listener,
+    protocols::socks5::{Credentials, ReplyError},
+};
+
+// 请求头最大长度，超过直接回 431
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 64;
+
+pub const CONNECT_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+
+#[derive(Debug, PartialEq)]
+pub struct HttpRequest {
+    pub dest: Destination,
+    // CONNECT 请求建立隧道，否则是要转发的普通请求
+    pub tunnel: bool,
+    // 改写后发给 server 的请求头，CONNECT 时为空
+    pub head: Vec<u8>,
+    // Proxy-Authorization 里的 username, password
+    pub credentials: Option<(String, Vec<u8>)>,
+    // 还没读到的请求体，CONNECT 时为 Length(0)
+    pub body: RequestBody,
+}
+
+// 请求体的长度，用来找到第一个请求在哪里结束
+// https://tools.ietf.org/html/rfc7230#section-3.3.3
+#[derive(Debug, PartialEq)]
+pub enum RequestBody {
+    // 剩余的字节数，没有 Content-Length 和 Transfer-Encoding 时为 0
+    Length(u64),
+    Chunked(Chunked),
+}
+
+// chunked 编码逐字节解析，只找结尾，数据原样转发
+// https://tools.ietf.org/html/rfc7230#section-4.1
+#[derive(Debug, PartialEq)]
+pub enum Chunked {
+    // chunk-size 行，分号之后是 chunk-ext
+    Size { size: u64, digits: usize, ext: bool },
+    Data(u64),
+    // chunk-data 后面的 CRLF
+    DataEnd,
+    // last-chunk 之后的 trailer，空行结束
+    Trailer { empty: bool },
+    Done,
+}
+
+impl RequestBody {
+    pub fn is_done(&self) -> bool {
+        matches!(
+            self,
+            RequestBody::Length(0) | RequestBody::Chunked(Chunked::Done)
+        )
+    }
+    // 返回 data 开头属于请求体的字节数，之后的是下一个请求
+    pub fn consume(&mut self, data: &[u8]) -> io::Result<usize> {
+        let chunked = match self {
+            RequestBody::Length(remaining) => {
+                let len = (*remaining).min(data.len() as u64);
+                *remaining -= len;
+                return Ok(len as usize);
+            }
+            RequestBody::Chunked(chunked) => chunked,
+        };
+        let mut pos = 0;
+        while pos < data.len() {
+            let b = data[pos];
+            *chunked = match *chunked {
+                Chunked::Done => break,
+                // chunk-data 整段跳过
+                Chunked::Data(remaining) => {
+                    let len = remaining.min((data.len() - pos) as u64);
+                    pos += len as usize;
+                    *chunked = match remaining - len {
+                        0 => Chunked::DataEnd,
+                        remaining => Chunked::Data(remaining),
+                    };
+                    continue;
+                }
+                Chunked::Size { size, digits, ext } => match b {
+                    b'\n' if digits == 0 => {
+                        return invalid_request("http proxy, invalid chunk size")
+                    }
+                    b'\n' if size == 0 => Chunked::Trailer { empty: true },
+                    b'\n' => Chunked::Data(size),
+                    b'\r' => Chunked::Size { size, digits, ext },
+                    _ if ext => Chunked::Size { size, digits, ext },
+                    b';' | b' ' | b'\t' => Chunked::Size {
+                        size,
+                        digits,
+                        ext: true,
+                    },
+                    // 最多 16 个十六进制数字，不会溢出
+                    _ if digits < 16 => match (b as char).to_digit(16) {
+                        Some(d) => Chunked::Size {
+                            size: size << 4 | d as u64,
+                            digits: digits + 1,
+                            ext,
+                        },
+                        None => return invalid_request("http proxy, invalid chunk size"),
+                    },
+                    _ => return invalid_request("http proxy, invalid chunk size"),
+                },
+                Chunked::DataEnd => match b {
+                    b'\r' => Chunked::DataEnd,
+                    b'\n' => Chunked::Size {
+                        size: 0,
+                        digits: 0,
+                        ext: false,
+                    },
+                    _ => return invalid_request("http proxy, invalid chunk data"),
+                },
+                Chunked::Trailer { empty } => match b {
+                    b'\r' => Chunked::Trailer { empty },
+                    b'\n' if empty => Chunked::Done,
+                    b'\n' => Chunked::Trailer { empty: true },
+                    _ => Chunked::Trailer { empty: false },
+                },
+            };
+            pos += 1;
+        }
+        Ok(pos)
+    }
+}
+
+fn invalid_request<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(ErrorKind::InvalidData, msg))
+}
+
+// 请求头太大用 InvalidInput 和格式错误区分开，回复 431
+fn header_too_large<T>() -> io::Result<T> {
+    Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        "http proxy, request header too large",
+    ))
+}
+
+// 读取并解析请求头，读多了的数据（请求体或 tls client hello）原样放在 pending 里
+// 出错时已经给 client 回复了对应的状态码
+pub async fn accept(
+    stream: &mut TcpStream,
+    listener: &Listener,
+) -> io::Result<(HttpRequest, Option<String>, Option<Bytes>)> {
+    let (request, mut buf) = match read_request(stream).await {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            let status = match err.kind() {
+                ErrorKind::UnexpectedEof => return Err(err),
+                ErrorKind::InvalidInput => "431 Request Header Fields Too Large",
+                _ => "400 Bad Request",
+            };
+            stream.write_all(&response(status, &[])).await?;
+            return Err(err);
+        }
+    };
+    let user = if listener.requires_auth() {
+        match request.credentials {
+            Some((ref username, ref password)) if listener.verify_user(username, password) => {
+                debug!("http proxy, user {} authenticated", username);
+                Some(username.clone())
+            }
+            _ => {
+                // https://tools.ietf.org/html/rfc7235#section-3.2
+                let challenge = "Proxy-Authenticate: Basic realm=\"ooproxy\"";
+                stream
+                    .write_all(&response("407 Proxy Authentication Required", &[challenge]))
+                    .await?;
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "http proxy, authentication failed",
+                ));
+            }
+        }
+    } else {
+        None
+    };
+    // 转发的请求头放在最前面，跟着 client 已经发来的请求体
+    let pending = if request.tunnel {
+        if buf.is_empty() {
+            None
+        } else {
+            Some(buf.freeze())
+        }
+    } else {
+        let mut data = BytesMut::with_capacity(request.head.len() + buf.len());
+        data.extend_from_slice(&request.head);
+        data.extend_from_slice(&buf.split());
+        Some(data.freeze())
+    };
+    Ok((request, user, pending))
+}
+
+async fn read_request(stream: &mut TcpStream) -> io::Result<(HttpRequest, BytesMut)> {
+    let mut buf = BytesMut::with_capacity(4096);
+    loop {
+        if let Some((mut request, len)) = parse_request(&buf)? {
+            let _ = buf.split_to(len);
+            // 普通请求只留下第一个请求的请求体
+            if !request.tunnel {
+                let len = request.body.consume(&buf)?;
+                if len < buf.len() {
+                    debug!(
+                        "http proxy, drop {} bytes of pipelined requests",
+                        buf.len() - len
+                    );
+                    buf.truncate(len);
+                }
+            }
+            return Ok((request, buf));
+        }
+        if buf.len() >= MAX_HEADER_SIZE {
+            return header_too_large();
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "http proxy, connection closed before request header",
+            ));
+        }
+    }
+}
+
+// 数据不够时返回 None
+pub fn parse_request(buf: &[u8]) -> io::Result<Option<(HttpRequest, usize)>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let len = match req.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(httparse::Error::TooManyHeaders) => return header_too_large(),
+        Err(_) => return invalid_request("http proxy, invalid request"),
+    };
+    let method = req.method.unwrap_or_default();
+    let target = req.path.unwrap_or_default();
+    let credentials = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("proxy-authorization"))
+        .and_then(|h| parse_basic_auth(h.value));
+    if method.eq_ignore_ascii_case("CONNECT") {
+        // CONNECT 的 target 是 authority-form，必须带端口
+        let dest = match parse_authority(target, None) {
+            Some(dest) => dest,
+            None => return invalid_request("http proxy, invalid CONNECT target"),
+        };
+        let request = HttpRequest {
+            dest,
+            tunnel: true,
+            head: Vec::new(),
+            credentials,
+            body: RequestBody::Length(0),
+        };
+        return Ok(Some((request, len)));
+    }
+    // 普通请求只支持 http://host[:port]/path
+    let rest = match target.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &target[7..],
+        _ => return invalid_request("http proxy, expect absolute http URI"),
+    };
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    // 去掉 userinfo
+    let authority = authority.rsplit('@').next().unwrap_or(authority);
+    let dest = match parse_authority(authority, Some(80)) {
+        Some(dest) => dest,
+        None => return invalid_request("http proxy, invalid host in URI"),
+    };
+    let path = match path {
+        "" => String::from("/"),
+        p if p.starts_with('?') => format!("/{}", p),
+        p => String::from(p),
+    };
+    let body = request_body(req.headers)?;
+    let head = rewrite_head(
+        method,
+        &path,
+        req.version.unwrap_or(1),
+        req.headers,
+        authority,
+    );
+    let request = HttpRequest {
+        dest,
+        tunnel: false,
+        head,
+        credentials,
+        body,
+    };
+    Ok(Some((request, len)))
+}
+
+// Transfer-Encoding 优先于 Content-Length，两个都有时可能是请求走私，直接拒绝
+// https://tools.ietf.org/html/rfc7230#section-3.3.3
+fn request_body(headers: &[httparse::Header<'_>]) -> io::Result<RequestBody> {
+    let mut body = None;
+    for header in headers.iter() {
+        let value = std::str::from_utf8(header.value).unwrap_or_default().trim();
+        let next = if header.name.eq_ignore_ascii_case("transfer-encoding") {
+            // 最后一个编码必须是 chunked，否则不知道请求在哪里结束
+            match value.rsplit(',').next() {
+                Some(coding) if coding.trim().eq_ignore_ascii_case("chunked") => {
+                    RequestBody::Chunked(Chunked::Size {
+                        size: 0,
+                        digits: 0,
+                        ext: false,
+                    })
+                }
+                _ => return invalid_request("http proxy, unsupported transfer coding"),
+            }
+        } else if header.name.eq_ignore_ascii_case("content-length") {
+            match value.parse() {
+                Ok(len) => RequestBody::Length(len),
+                Err(_) => return invalid_request("http proxy, invalid content length"),
+            }
+        } else {
+            continue;
+        };
+        // 重复的 Content-Length 只允许值相同
+        match body {
+            Some(ref body) if *body != next => {
+                return invalid_request("http proxy, conflicting request body length")
+            }
+            _ => body = Some(next),
+        }
+    }
+    Ok(body.unwrap_or(RequestBody::Length(0)))
+}
+
+// 改写成 origin-form，去掉 Proxy-* 和逐跳的头
+// 每个连接只转发一个请求，所以强制 Connection: close
+// https://tools.ietf.org/html/rfc7230#section-6.1
+fn rewrite_head(
+    method: &str,
+    path: &str,
+    version: u8,
+    headers: &[httparse::Header<'_>],
+    authority: &str,
+) -> Vec<u8> {
+    // Connection 里列出的头也是逐跳的
+    let mut hop_by_hop: Vec<String> = vec![String::from("connection"), String::from("keep-alive")];
+    for header in headers.iter() {
+        if header.name.eq_ignore_ascii_case("connection")
+            || header.name.eq_ignore_ascii_case("proxy-connection")
+        {
+            let value = String::from_utf8_lossy(header.value);
+            hop_by_hop.extend(value.split(',').map(|v| v.trim().to_ascii_lowercase()));
+        }
+    }
+    let mut head = format!("{} {} HTTP/1.{}\r\n", method, path, version).into_bytes();
+    let mut has_host = false;
+    for header in headers.iter() {
+        let name = header.name.to_ascii_lowercase();
+        if name.starts_with("proxy-") || hop_by_hop.contains(&name) {
+            continue;
+        }
+        has_host |= name == "host";
+        head.extend(header.name.as_bytes());
+        head.extend(b": ");
+        head.extend(header.value);
+        head.extend(b"\r\n");
+    }
+    if !has_host {
+        head.extend(format!("Host: {}\r\n", authority).as_bytes());
+    }
+    head.extend(b"Connection: close\r\n\r\n");
+    head
+}
+
+// host:port 或者 [v6]:port，没有端口时用 default_port
+fn parse_authority(authority: &str, default_port: Option<u16>) -> Option<Destination> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let end = rest.find(']')?;
+        let port = match &rest[end + 1..] {
+            "" => None,
+            port => Some(port.strip_prefix(':')?),
+        };
+        (&rest[..end], port)
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port?,
+    };
+    // 转给 socks5 upstream 时域名长度只有 1 字节
+    if host.is_empty() || host.len() > 255 {
+        return None;
+    }
+    let host = match host.parse::<IpAddr>() {
+        Ok(ip) => Address::Ip(ip),
+        Err(_) => Address::Domain(host.to_ascii_lowercase().into_boxed_str()),
+    };
+    Some((host, port).into())
+}
+
+// Basic base64(username:password)
+// https://tools.ietf.org/html/rfc7617
+fn parse_basic_auth(value: &[u8]) -> Option<(String, Vec<u8>)> {
+    let value = std::str::from_utf8(value).ok()?.trim();
+    let (scheme, token) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::decode(token.trim()).ok()?;
+    let colon = decoded.iter().position(|&b| b == b':')?;
+    let username = String::from_utf8(decoded[..colon].to_vec()).ok()?;
+    Some((username, decoded[colon + 1..].to_vec()))
+}
+
+fn response(status: &str, headers: &[&str]) -> Vec<u8> {
+    let mut buf = format!("HTTP/1.1 {}\r\n", status);
+    for header in headers {
+        buf.push_str(header);
+        buf.push_str("\r\n");
+    }
+    buf.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+    buf.into_bytes()
+}
+
+// 转发普通请求，只把第一个请求的请求体发给 server，之后不再读 client
+// server 看到 Connection: close 响应完就关闭，这时也关闭 client
+pub async fn relay(
+    mut left: TcpStream,
+    mut remote: TcpStream,
+    mut body: RequestBody,
+) -> io::Result<()> {
+    let (mut left_read, mut left_write) = left.split();
+    let (mut remote_read, mut remote_write) = remote.split();
+    let request = async {
+        let mut buf = vec![0u8; 8 * 1024];
+        while !body.is_done() {
+            let n = left_read.read(&mut buf).await?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "http proxy, connection closed before request body",
+                ));
+            }
+            let len = body.consume(&buf[..n])?;
+            remote_write.write_all(&buf[..len]).await?;
+            if len < n {
+                debug!("http proxy, drop {} bytes of pipelined requests", n - len);
+            }
+        }
+        Ok(())
+    };
+    let response = async {
+        tokio_io::copy(&mut remote_read, &mut left_write).await?;
+        left_write.shutdown().await
+    };
+    tokio::pin!(request, response);
+    tokio::select! {
+        result = &mut request => {
+            result?;
+            response.await
+        }
+        // server 不等请求体发完就响应了
+        result = &mut response => result,
+    }
+}
+
+// 连接 upstream 失败时回复给 client 的状态码
+pub fn error_response(err: &io::Error) -> Vec<u8> {
+    let status = match ReplyError::from(err) {
+        ReplyError::NotAllowed => "403 Forbidden",
+        ReplyError::TtlExpired => "504 Gateway Timeout",
+        _ => "502 Bad Gateway",
+    };
+    response(status, &[])
+}
+
+// 作为 client 通过 upstream 的 http 代理建立隧道
+// 隧道建立后把 client 已经发来的数据写过去
+pub async fn connect<S, T>(
+    remote: &mut S,
+    dest: &Destination,
+    data: Option<T>,
+    auth: Option<Credentials<'_>>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: AsRef<[u8]>,
+{
+    remote.write_all(&build_connect(dest, auth)).await?;
+    let head = read_response_head(remote).await?;
+    parse_response(&head)?;
+    if let Some(data) = data {
+        debug!("Early data has been flushed into socket after http CONNECT");
+        remote.write_all(data.as_ref()).await?;
+    }
+    Ok(())
+}
+
+fn build_connect(dest: &Destination, auth: Option<Credentials<'_>>) -> Vec<u8> {
+    // Display 会给 ipv6 加上 []
+    let mut buf = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", dest);
+    if let Some((username, password)) = auth {
+        let token = base64::encode(format!("{}:{}", username, password));
+        buf.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    buf.push_str("\r\n");
+    buf.into_bytes()
+}
+
+// 一个字节一个字节地读到空行，不能多读
+// 紧跟着响应头的数据属于隧道，要留给 pipe
+async fn read_response_head<R>(remote: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(256);
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() >= MAX_HEADER_SIZE {
+            return invalid_request("upstream http proxy response header too large");
+        }
+        buf.push(remote.read_u8().await?);
+    }
+    Ok(buf)
+}
+
+// 2xx 表示隧道已经建立，其他状态码按含义转成对应的错误
+fn parse_response(buf: &[u8]) -> io::Result<()> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut resp = httparse::Response::new(&mut headers);
+    match resp.parse(buf) {
+        Ok(httparse::Status::Complete(_)) => (),
+        _ => return invalid_request("invalid response from upstream http proxy"),
+    }
+    let code = resp.code.unwrap_or_default();
+    if (200..300).contains(&code) {
+        return Ok(());
+    }
+    let kind = match code {
+        403 | 407 => ErrorKind::PermissionDenied,
+        408 | 504 => ErrorKind::TimedOut,
+        502 => ErrorKind::HostUnreachable,
+        _ => ErrorKind::Other,
+    };
+    Err(io::Error::new(
+        kind,
+        format!(
+            "upstream http proxy replied {} {}",
+            code,
+            resp.reason.unwrap_or_default()
+        ),
+    ))
+}
+
+#[test]
+fn test_parse_connect() {
+    let buf = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n\x16\x03\x01";
+    let (request, len) = parse_request(buf).unwrap().unwrap();
+    assert!(request.tunnel);
+    assert_eq!(request.dest, ("example.com", 443).into());
+    assert_eq!(&buf[len..], b"\x16\x03\x01");
+    let (request, _) = parse_request(b"CONNECT [::1]:8443 HTTP/1.1\r\n\r\n")
+        .unwrap()
+        .unwrap();
+    assert_eq!(request.dest.to_string(), "[::1]:8443");
+    // CONNECT 必须带端口
+    assert!(parse_request(b"CONNECT example.com HTTP/1.1\r\n\r\n").is_err());
+    assert!(parse_request(b"CONNECT example.com:443 HTTP/1.1\r\n")
+        .unwrap()
+        .is_none());
+    let head = format!("CONNECT {}:443 HTTP/1.1\r\n\r\n", "a".repeat(256));
+    assert!(parse_request(head.as_bytes()).is_err());
+}
+
+#[test]
+fn test_rewrite_absolute_uri() {
+    let buf = b"GET http://user@Example.com:8080?q=1 HTTP/1.1\r\n\
+        Host: example.com:8080\r\n\
+        Proxy-Connection: keep-alive, X-Trace\r\n\
+        Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\
+        X-Trace: 1\r\n\
+        Accept: */*\r\n\r\n";
+    let (request, len) = parse_request(buf).unwrap().unwrap();
+    assert_eq!(len, buf.len());
+    assert!(!request.tunnel);
+    assert_eq!(request.dest, ("example.com", 8080).into());
+    assert_eq!(
+        request.credentials,
+        Some((String::from("alice"), b"secret".to_vec()))
+    );
+    assert_eq!(
+        String::from_utf8(request.head).unwrap(),
+        "GET /?q=1 HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+    );
+    let (request, _) = parse_request(b"POST http://10.0.0.1/a/b HTTP/1.0\r\n\r\n")
+        .unwrap()
+        .unwrap();
+    assert_eq!(request.dest.to_string(), "10.0.0.1:80");
+    assert_eq!(
+        String::from_utf8(request.head).unwrap(),
+        "POST /a/b HTTP/1.0\r\nHost: 10.0.0.1\r\nConnection: close\r\n\r\n"
+    );
+    assert!(parse_request(b"GET /index.html HTTP/1.1\r\n\r\n").is_err());
+    assert!(parse_request(b"GET https://example.com/ HTTP/1.1\r\n\r\n").is_err());
+}
+
+#[tokio::test]
+async fn test_connect_outbound() {
+    let (mut local, mut proxy) = tokio::io::duplex(1024);
+    let dest: Destination = ("example.com", 443).into();
+    let server = tokio::spawn(async move {
+        let mut buf = vec![0u8; 1024];
+        let n = proxy.read(&mut buf).await.unwrap();
+        buf.truncate(n);
+        // 响应头后面紧跟着隧道里的数据
+        proxy
+            .write_all(b"HTTP/1.1 200 Connection established\r\nVia: test\r\n\r\nbanner")
+            .await
+            .unwrap();
+        let mut early = [0u8; 5];
+        proxy.read_exact(&mut early).await.unwrap();
+        (buf, early)
+    });
+    connect(&mut local, &dest, Some(b"hello"), Some(("alice", "secret")))
+        .await
+        .unwrap();
+    let mut banner = [0u8; 6];
+    local.read_exact(&mut banner).await.unwrap();
+    assert_eq!(&banner, b"banner");
+    let (request, early) = server.await.unwrap();
+    assert_eq!(
+        String::from_utf8(request).unwrap(),
+        "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\
+        Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n"
+    );
+    assert_eq!(&early, b"hello");
+
+    let err = parse_response(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert!(err.to_string().contains("407"), "{}", err);
+    let err = parse_response(b"HTTP/1.0 504 Gateway Timeout\r\n\r\n").unwrap_err();
+    assert_eq!(ReplyError::from(&err), ReplyError::TtlExpired);
+    assert!(parse_response(b"SSH-2.0-OpenSSH\r\n\r\n").is_err());
+}
+
+#[test]
+fn test_request_body() {
+    let (request, len) = parse_request(b"GET http://a/ HTTP/1.1\r\n\r\n")
+        .unwrap()
+        .unwrap();
+    assert_eq!(len, 26);
+    assert!(request.body.is_done());
+    let (mut request, _) = parse_request(b"POST http://a/ HTTP/1.1\r\nContent-Length: 5\r\n\r\n")
+        .unwrap()
+        .unwrap();
+    assert_eq!(request.body.consume(b"abc").unwrap(), 3);
+    assert_eq!(request.body.consume(b"deGET ").unwrap(), 2);
+    assert!(request.body.is_done());
+
+    // chunk 和 trailer 分几次到达
+    let (mut request, _) =
+        parse_request(b"POST http://a/ HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n")
+            .unwrap()
+            .unwrap();
+    let body =
+        b"5;name=v\r\nhello\r\n1A\r\nabcdefghijklmnopqrstuvwxyz\r\n0\r\nX-Sum: 1\r\n\r\nGET ";
+    let mut pos = 0;
+    for chunk in body.chunks(7) {
+        pos += request.body.consume(chunk).unwrap();
+        if request.body.is_done() {
+            break;
+        }
+    }
+    assert_eq!(&body[pos..], b"GET ");
+
+    let mut body = RequestBody::Chunked(Chunked::Size {
+        size: 0,
+        digits: 0,
+        ext: false,
+    });
+    assert!(body.consume(b"zz\r\n").is_err());
+    for head in [
+        "Transfer-Encoding: chunked\r\nContent-Length: 5\r\n",
+        "Content-Length: 5\r\nContent-Length: 6\r\n",
+        "Content-Length: -1\r\n",
+        "Transfer-Encoding: gzip\r\n",
+    ] {
+        let buf = format!("POST http://a/ HTTP/1.1\r\n{}\r\n", head);
+        assert!(parse_request(buf.as_bytes()).is_err(), "{}", head);
+    }
+}
+
+// pipeline 的第二个请求不能原样带着 Proxy-Authorization 发给 server
+#[tokio::test]
+async fn test_relay_pipelined() {
+    use tokio::net::TcpListener;
+
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(proxy.local_addr().unwrap())
+        .await
+        .unwrap();
+    let request = "POST http://example.com/a HTTP/1.1\r\n\
+        Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\
+        Content-Length: 4\r\n\r\nbody\
+        GET http://example.com/b HTTP/1.1\r\n\
+        Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n";
+    client.write_all(request.as_bytes()).await.unwrap();
+    let (mut left, _) = proxy.accept().await.unwrap();
+    let (request, _, pending) = accept(&mut left, &Listener::new(None, 0)).await.unwrap();
+    let mut remote = TcpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    remote.write_all(&pending.unwrap()).await.unwrap();
+    let relay = tokio::spawn(relay(left, remote, request.body));
+
+    let (mut origin, _) = server.accept().await.unwrap();
+    origin
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+        .await
+        .unwrap();
+    origin.shutdown().await.unwrap();
+    relay.await.unwrap().unwrap();
+    // relay 结束时关闭了 remote，读到的就是 server 收到的全部数据
+    let mut received = Vec::new();
+    origin.read_to_end(&mut received).await.unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&received),
+        "POST /a HTTP/1.1\r\nContent-Length: 4\r\nHost: example.com\r\n\
+        Connection: close\r\n\r\nbody"
+    );
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert!(response.ends_with(b"\r\n\r\nok"));
+}
@@ -1,2 +1,5 @@
-mod socks5;
-pub use self::socks5::handshake;
+pub mod detect;
+pub mod http;
+pub mod socks4;
+pub mod socks5;
+pub use self::socks5::{handshake, Credentials};
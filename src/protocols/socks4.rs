@@ -0,0 +1,144 @@
+// socks4 和 socks4a 入站，只支持 CONNECT
+// https://www.openssh.com/txt/socks4.protocol
+// https://www.openssh.com/txt/socks4a.protocol
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr},
+};
+
+#[cfg(test)]
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::client::{Address, Destination};
+
+pub const VERSION: u8 = 0x04;
+
+pub const CMD_CONNECT: u8 = 0x01;
+pub const CMD_BIND: u8 = 0x02;
+
+// 回复的 VN 是 0
+pub const REP_GRANTED: u8 = 0x5a;
+pub const REP_REJECTED: u8 = 0x5b;
+
+// USERID 和 4a 的域名都以 NULL 结尾，限制长度避免一直读
+const MAX_FIELD_LEN: usize = 255;
+
+// +----+----+----+----+----+----+----+----+----+----+....+----+
+// | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+// +----+----+----+----+----+----+----+----+----+----+....+----+
+//    1    1      2              4           variable       1
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub command: u8,
+    pub dest: Destination,
+    pub userid: String,
+}
+
+impl Request {
+    pub async fn read_from<R>(reader: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf).await?;
+        if buf[0] != VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Socksv4: unknown request version",
+            ));
+        }
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+        let userid = read_string(reader).await?;
+        // 4a: DSTIP 是 0.0.0.x（x 不为 0）时，USERID 后面跟着域名，由 server 解析
+        let octets = ip.octets();
+        let host = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+            let domain = read_string(reader).await?;
+            if domain.is_empty() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Socksv4a: empty domain name",
+                ));
+            }
+            Address::Domain(domain.into_boxed_str())
+        } else {
+            Address::Ip(ip.into())
+        };
+        Ok(Request {
+            command: buf[1],
+            dest: (host, port).into(),
+            userid,
+        })
+    }
+}
+
+// 读到 NULL 为止
+async fn read_string<R>(reader: &mut R) -> io::Result<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_u8().await? {
+            0 => break,
+            _ if buf.len() >= MAX_FIELD_LEN => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Socksv4: field too long",
+                ))
+            }
+            b => buf.push(b),
+        }
+    }
+    String::from_utf8(buf)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Socksv4: invalid string"))
+}
+
+// +----+----+----+----+----+----+----+----+
+// | VN | CD | DSTPORT |      DSTIP        |
+// +----+----+----+----+----+----+----+----+
+// client 会忽略 DSTPORT 和 DSTIP，有 ipv4 地址时还是填上
+pub fn build_reply(rep: u8, bind: Option<&Destination>) -> [u8; 8] {
+    let mut buf = [0u8; 8];
+    buf[1] = rep;
+    if let Some(Destination {
+        host: Address::Ip(IpAddr::V4(ip)),
+        port,
+    }) = bind
+    {
+        buf[2..4].copy_from_slice(&port.to_be_bytes());
+        buf[4..].copy_from_slice(&ip.octets());
+    }
+    buf
+}
+
+#[tokio::test]
+async fn test_read_request() {
+    let mut buf: &[u8] = &[4, 1, 0, 80, 93, 184, 216, 34, b'b', b'o', b'b', 0];
+    let request = Request::read_from(&mut buf).await.unwrap();
+    assert_eq!(request.command, CMD_CONNECT);
+    assert_eq!(request.dest.to_string(), "93.184.216.34:80");
+    assert_eq!(request.userid, "bob");
+    // 4a
+    let mut buf: &[u8] = b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com\x00";
+    let request = Request::read_from(&mut buf).await.unwrap();
+    assert_eq!(request.dest, ("example.com", 443).into());
+    assert_eq!(request.userid, "");
+    // 没有 NULL 结尾
+    let mut buf: &[u8] = b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com";
+    assert!(Request::read_from(&mut buf).await.is_err());
+    let long = [vec![4, 1, 0, 80, 1, 2, 3, 4], vec![b'a'; 300], vec![0]].concat();
+    assert!(Request::read_from(&mut &long[..]).await.is_err());
+}
+
+#[test]
+fn test_build_reply() {
+    let bound: Destination = SocketAddr::from(([10, 0, 0, 1], 1080)).into();
+    assert_eq!(
+        build_reply(REP_GRANTED, Some(&bound)),
+        [0, 0x5a, 0x04, 0x38, 10, 0, 0, 1]
+    );
+    assert_eq!(build_reply(REP_REJECTED, None), [0, 0x5b, 0, 0, 0, 0, 0, 0]);
+}
@@ -0,0 +1,545 @@
+// socks5 报文的编解码，client 和 server 两边共用
+// https://tools.ietf.org/html/rfc1928
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+#[cfg(test)]
+use std::net::Ipv6Addr;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::client::{Address, Destination};
+
+pub const VERSION: u8 = 0x05;
+
+pub const CMD_CONNECT: u8 = 0x01;
+pub const CMD_BIND: u8 = 0x02;
+pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+pub const METHOD_NO_AUTH: u8 = 0x00;
+pub const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+pub const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+
+pub const REP_SUCCEEDED: u8 = 0x00;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+fn invalid_data<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(ErrorKind::InvalidData, msg))
+}
+
+// REP 字段里的错误码
+// https://tools.ietf.org/html/rfc1928#section-6
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplyError {
+    // X'01'
+    GeneralFailure,
+    // X'02'
+    NotAllowed,
+    // X'03'
+    NetworkUnreachable,
+    // X'04'
+    HostUnreachable,
+    // X'05'
+    ConnectionRefused,
+    // X'06'
+    TtlExpired,
+    // X'07'
+    CommandNotSupported,
+    // X'08'
+    AddressTypeNotSupported,
+    // X'09' to X'FF' unassigned
+    Unassigned(u8),
+}
+
+impl ReplyError {
+    // 0x00 是成功，不是错误
+    pub fn from_code(rep: u8) -> Option<Self> {
+        let err = match rep {
+            REP_SUCCEEDED => return None,
+            0x01 => ReplyError::GeneralFailure,
+            0x02 => ReplyError::NotAllowed,
+            0x03 => ReplyError::NetworkUnreachable,
+            0x04 => ReplyError::HostUnreachable,
+            0x05 => ReplyError::ConnectionRefused,
+            0x06 => ReplyError::TtlExpired,
+            0x07 => ReplyError::CommandNotSupported,
+            0x08 => ReplyError::AddressTypeNotSupported,
+            code => ReplyError::Unassigned(code),
+        };
+        Some(err)
+    }
+    pub fn code(&self) -> u8 {
+        match *self {
+            ReplyError::GeneralFailure => 0x01,
+            ReplyError::NotAllowed => 0x02,
+            ReplyError::NetworkUnreachable => 0x03,
+            ReplyError::HostUnreachable => 0x04,
+            ReplyError::ConnectionRefused => 0x05,
+            ReplyError::TtlExpired => 0x06,
+            ReplyError::CommandNotSupported => 0x07,
+            ReplyError::AddressTypeNotSupported => 0x08,
+            ReplyError::Unassigned(code) => code,
+        }
+    }
+    // io::Error 里带的 ReplyError，用来区分 upstream 回复的错误码
+    pub fn from_io_error(err: &io::Error) -> Option<Self> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<ReplyError>())
+            .copied()
+    }
+}
+
+// 我们作为 server 时，把连接 upstream 的错误转成回复给 client 的 REP
+// upstream 回复的错误码原样转发
+impl From<&io::Error> for ReplyError {
+    fn from(err: &io::Error) -> Self {
+        if let Some(rep) = ReplyError::from_io_error(err) {
+            return rep;
+        }
+        match err.kind() {
+            ErrorKind::NetworkUnreachable => ReplyError::NetworkUnreachable,
+            ErrorKind::HostUnreachable => ReplyError::HostUnreachable,
+            ErrorKind::ConnectionRefused => ReplyError::ConnectionRefused,
+            ErrorKind::TimedOut => ReplyError::TtlExpired,
+            _ => ReplyError::GeneralFailure,
+        }
+    }
+}
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ReplyError::GeneralFailure => write!(f, "general SOCKS server failure"),
+            ReplyError::NotAllowed => write!(f, "connection not allowed by ruleset"),
+            ReplyError::NetworkUnreachable => write!(f, "network unreachable"),
+            ReplyError::HostUnreachable => write!(f, "host unreachable"),
+            ReplyError::ConnectionRefused => write!(f, "connection refused"),
+            ReplyError::TtlExpired => write!(f, "TTL expired"),
+            ReplyError::CommandNotSupported => write!(f, "command not supported"),
+            ReplyError::AddressTypeNotSupported => write!(f, "address type not supported"),
+            ReplyError::Unassigned(code) => write!(f, "unassigned reply code {:#04x}", code),
+        }
+    }
+}
+
+impl Error for ReplyError {}
+
+// 保留 ReplyError 本身，调用方可以用 ReplyError::from_io_error 取回
+impl From<ReplyError> for io::Error {
+    fn from(err: ReplyError) -> Self {
+        let kind = match err {
+            ReplyError::NotAllowed => ErrorKind::PermissionDenied,
+            ReplyError::ConnectionRefused => ErrorKind::ConnectionRefused,
+            ReplyError::TtlExpired => ErrorKind::TimedOut,
+            ReplyError::CommandNotSupported | ReplyError::AddressTypeNotSupported => {
+                ErrorKind::Unsupported
+            }
+            _ => ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+// +----+----------+----------+
+// |VER | NMETHODS | METHODS  |
+// +----+----------+----------+
+// | 1  |    1     | 1 to 255 |
+// +----+----------+----------+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Greeting {
+    pub methods: Vec<u8>,
+}
+
+impl Greeting {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(VERSION);
+        buf.push(self.methods.len() as u8);
+        buf.extend(&self.methods);
+    }
+    // 数据不够时返回 None，否则返回报文和消耗的字节数
+    pub fn decode(buf: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        if buf[0] != VERSION {
+            return invalid_data("Socksv5: unknown greeting version");
+        }
+        let len = 2 + buf[1] as usize;
+        match buf.get(2..len) {
+            Some(methods) => Ok(Some((
+                Greeting {
+                    methods: methods.to_vec(),
+                },
+                len,
+            ))),
+            None => Ok(None),
+        }
+    }
+    pub async fn read_from<R>(reader: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = vec![0u8; 2];
+        reader.read_exact(&mut buf).await?;
+        if buf[0] != VERSION {
+            return invalid_data("Socksv5: unknown greeting version");
+        }
+        buf.resize(2 + buf[1] as usize, 0);
+        reader.read_exact(&mut buf[2..]).await?;
+        Ok(Greeting {
+            methods: buf.split_off(2),
+        })
+    }
+}
+
+// +----+-----+-------+------+----------+----------+
+// |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
+// +----+-----+-------+------+----------+----------+
+// | 1  |  1  | X'00' |  1   | Variable |    2     |
+// +----+-----+-------+------+----------+----------+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub command: u8,
+    pub dest: Destination,
+}
+
+impl Request {
+    pub fn encode(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend(&[VERSION, self.command, 0x00]);
+        write_address(buf, &self.dest)
+    }
+    // CMD 不在这里检查，由 server 决定回复 X'07'
+    pub fn decode(buf: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        Ok(decode_message(buf, "Socksv5: unknown request version")?
+            .map(|(command, dest, len)| (Request { command, dest }, len)))
+    }
+    pub async fn read_from<R>(reader: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let (command, dest) = read_message(reader, "Socksv5: unknown request version").await?;
+        Ok(Request { command, dest })
+    }
+}
+
+// +----+-----+-------+------+----------+----------+
+// |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+// +----+-----+-------+------+----------+----------+
+// | 1  |  1  | X'00' |  1   | Variable |    2     |
+// +----+-----+-------+------+----------+----------+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub rep: u8,
+    pub bind: Destination,
+}
+
+impl Reply {
+    pub fn new(rep: u8, bind: Destination) -> Self {
+        Reply { rep, bind }
+    }
+    // 失败的回复没有有意义的地址，填全 0
+    pub fn error(err: ReplyError) -> Self {
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        Reply::new(err.code(), unspecified.into())
+    }
+    pub fn encode(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend(&[VERSION, self.rep, 0x00]);
+        write_address(buf, &self.bind)
+    }
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(22);
+        self.encode(&mut buf)?;
+        Ok(buf)
+    }
+    pub fn decode(buf: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        Ok(decode_message(buf, "Socksv5: unknown reply version")?
+            .map(|(rep, bind, len)| (Reply { rep, bind }, len)))
+    }
+    pub async fn read_from<R>(reader: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let (rep, bind) = read_message(reader, "Socksv5: unknown reply version").await?;
+        Ok(Reply { rep, bind })
+    }
+    // REP 不是成功时转成对应的 ReplyError
+    pub fn into_result(self) -> io::Result<Destination> {
+        match ReplyError::from_code(self.rep) {
+            None => Ok(self.bind),
+            Some(err) => Err(err.into()),
+        }
+    }
+}
+
+// request 和 reply 的格式一样，只有第二个字节的含义不同
+fn decode_message(
+    buf: &[u8],
+    bad_version: &'static str,
+) -> io::Result<Option<(u8, Destination, usize)>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    if buf[0] != VERSION {
+        return invalid_data(bad_version);
+    }
+    Ok(decode_address(&buf[3..])?.map(|(dest, len)| (buf[1], dest, 3 + len)))
+}
+
+async fn read_message<R>(reader: &mut R, bad_version: &'static str) -> io::Result<(u8, Destination)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = vec![0u8; 4];
+    reader.read_exact(&mut buf).await?;
+    if buf[0] != VERSION {
+        return invalid_data(bad_version);
+    }
+    let addr_len = match buf[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let len = reader.read_u8().await?;
+            buf.push(len);
+            len as usize
+        }
+        _ => return Err(ReplyError::AddressTypeNotSupported.into()),
+    };
+    // 地址 + 2 字节端口
+    let start = buf.len();
+    buf.resize(start + addr_len + 2, 0);
+    reader.read_exact(&mut buf[start..]).await?;
+    let (dest, _) = parse_address(&buf[3..])?;
+    Ok((buf[1], dest))
+}
+
+// ATYP + ADDR + PORT
+// 域名的长度字段只有 1 字节，超过 255 字节时报错，不能截断长度
+pub fn write_address(buf: &mut Vec<u8>, dest: &Destination) -> io::Result<()> {
+    match dest.host {
+        Address::Ip(ip) => match ip {
+            IpAddr::V4(i) => {
+                buf.push(ATYP_IPV4);
+                //    the address is a version-4 IP address, with a length of 4 octets
+                buf.extend_from_slice(&i.octets())
+            }
+            IpAddr::V6(i) => {
+                buf.push(ATYP_IPV6);
+                //   the address is a version-6 IP address, with a length of 16 octets.
+                buf.extend(&i.octets());
+            }
+        },
+        Address::Domain(ref name) => {
+            if name.len() > 255 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Socksv5: domain name of {} bytes is too long", name.len()),
+                ));
+            }
+            buf.push(ATYP_DOMAIN);
+            buf.push(name.len() as u8);
+            buf.extend(name.as_bytes());
+        }
+    }
+    // 端口两字节
+    buf.extend(&dest.port.to_be_bytes());
+    Ok(())
+}
+
+// 从 ATYP 开始解析地址，数据不够时返回 None
+pub fn decode_address(buf: &[u8]) -> io::Result<Option<(Destination, usize)>> {
+    let (host, len): (Address, usize) = match buf.first() {
+        Some(&ATYP_IPV4) => match buf.get(1..5) {
+            Some(ip) => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(ip);
+                (octets.into(), 4)
+            }
+            None => return Ok(None),
+        },
+        Some(&ATYP_IPV6) => match buf.get(1..17) {
+            Some(ip) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(ip);
+                (octets.into(), 16)
+            }
+            None => return Ok(None),
+        },
+        Some(&ATYP_DOMAIN) => {
+            let len = match buf.get(1) {
+                Some(&len) => len as usize,
+                None => return Ok(None),
+            };
+            let name = match buf.get(2..2 + len) {
+                Some(name) => name,
+                None => return Ok(None),
+            };
+            if name.is_empty() {
+                return invalid_data("Socksv5: empty domain name");
+            }
+            let name = match String::from_utf8(name.to_vec()) {
+                Ok(name) => name,
+                Err(_) => return invalid_data("Socksv5: invalid domain name"),
+            };
+            (name.into(), len + 1)
+        }
+        Some(_) => return Err(ReplyError::AddressTypeNotSupported.into()),
+        None => return Ok(None),
+    };
+    match buf.get(1 + len..3 + len) {
+        Some(port) => {
+            let port = u16::from_be_bytes([port[0], port[1]]);
+            Ok(Some(((host, port).into(), 3 + len)))
+        }
+        None => Ok(None),
+    }
+}
+
+// 和 decode_address 一样，但数据不完整时直接报错
+pub fn parse_address(buf: &[u8]) -> io::Result<(Destination, usize)> {
+    match decode_address(buf)? {
+        Some(parsed) => Ok(parsed),
+        None => invalid_data("Socksv5: truncated address"),
+    }
+}
+
+// 作为 server 回复 client
+pub fn build_reply(rep: u8, bind: &Destination) -> io::Result<Vec<u8>> {
+    Reply::new(rep, bind.clone()).to_bytes()
+}
+
+// +----+------+------+----------+----------+----------+
+// |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+// +----+------+------+----------+----------+----------+
+// | 2  |  1   |  1   | Variable |    2     | Variable |
+// +----+------+------+----------+----------+----------+
+// 返回目标地址和 DATA
+pub fn parse_udp_header(buf: &[u8]) -> io::Result<(Destination, &[u8])> {
+    if buf.len() < 3 || buf[0..2] != [0x00, 0x00] {
+        return invalid_data("Socksv5: invalid udp header");
+    }
+    // 不支持分片，RFC 里分片是可选实现
+    if buf[2] != 0x00 {
+        return invalid_data("Socksv5: udp fragment is not supported");
+    }
+    let (dest, len) = parse_address(&buf[3..])?;
+    Ok((dest, &buf[3 + len..]))
+}
+
+pub fn build_udp_header(buf: &mut Vec<u8>, dest: &Destination) -> io::Result<()> {
+    buf.extend(&[0x00, 0x00, 0x00]);
+    write_address(buf, dest)
+}
+
+#[cfg(test)]
+fn test_destinations() -> Vec<Destination> {
+    vec![
+        SocketAddr::from(([1, 2, 3, 4], 80)).into(),
+        SocketAddr::from((Ipv6Addr::LOCALHOST, 443)).into(),
+        ("example.com", 8080).into(),
+    ]
+}
+
+#[test]
+fn test_address_round_trip() {
+    for dest in test_destinations() {
+        let mut buf = Vec::new();
+        build_udp_header(&mut buf, &dest).unwrap();
+        buf.extend(b"payload");
+        let (parsed, data) = parse_udp_header(&buf).unwrap();
+        assert_eq!(parsed, dest);
+        assert_eq!(data, b"payload");
+    }
+    assert!(parse_udp_header(&[0, 0, 1, 1, 127, 0, 0, 1, 0, 53]).is_err());
+    assert!(parse_udp_header(&[0, 0, 0, 3, 10, b'a']).is_err());
+}
+
+// 长度字段只有 1 字节，256 字节的域名不能截断成 0 长度发出去
+#[test]
+fn test_domain_too_long() {
+    let name = "a".repeat(255);
+    let mut buf = Vec::new();
+    build_udp_header(&mut buf, &(name.as_str(), 53).into()).unwrap();
+    assert_eq!(
+        parse_udp_header(&buf).unwrap().0,
+        (name.as_str(), 53).into()
+    );
+
+    let name = "a".repeat(256);
+    let dest: Destination = (name.as_str(), 53).into();
+    let mut buf = Vec::new();
+    let err = build_udp_header(&mut buf, &dest).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let request = Request {
+        command: CMD_CONNECT,
+        dest: dest.clone(),
+    };
+    assert!(request.encode(&mut Vec::new()).is_err());
+    assert!(Reply::new(REP_SUCCEEDED, dest).to_bytes().is_err());
+}
+
+#[test]
+fn test_message_round_trip() {
+    for dest in test_destinations() {
+        let request = Request {
+            command: CMD_BIND,
+            dest: dest.clone(),
+        };
+        let mut buf = Vec::new();
+        request.encode(&mut buf).unwrap();
+        // 每个前缀都应该是数据不够，而不是报错
+        for i in 0..buf.len() {
+            assert!(Request::decode(&buf[..i]).unwrap().is_none());
+        }
+        buf.push(0xaa);
+        let (decoded, len) = Request::decode(&buf).unwrap().unwrap();
+        assert_eq!(decoded, request);
+        assert_eq!(len, buf.len() - 1);
+
+        let reply = Reply::new(REP_SUCCEEDED, dest);
+        let mut buf = Vec::new();
+        reply.encode(&mut buf).unwrap();
+        let (decoded, len) = Reply::decode(&buf).unwrap().unwrap();
+        assert_eq!(decoded, reply);
+        assert_eq!(len, buf.len());
+    }
+    let greeting = Greeting {
+        methods: vec![METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD],
+    };
+    let mut buf = Vec::new();
+    greeting.encode(&mut buf);
+    assert_eq!(buf, [5, 2, 0, 2]);
+    assert!(Greeting::decode(&buf[..3]).unwrap().is_none());
+    assert_eq!(Greeting::decode(&buf).unwrap(), Some((greeting, 4)));
+    assert!(Greeting::decode(&[4, 1, 0]).is_err());
+    assert!(Reply::decode(&[5, 0, 0, 9, 0]).is_err());
+}
+
+#[test]
+fn test_reply_error() {
+    assert_eq!(ReplyError::from_code(0), None);
+    for code in 1..=0xff {
+        assert_eq!(ReplyError::from_code(code).unwrap().code(), code);
+    }
+    let err: io::Error = Reply::decode(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0])
+        .unwrap()
+        .unwrap()
+        .0
+        .into_result()
+        .unwrap_err();
+    assert_eq!(err.to_string(), "host unreachable");
+    assert_eq!(
+        ReplyError::from_io_error(&err),
+        Some(ReplyError::HostUnreachable)
+    );
+    let err: io::Error = ReplyError::NotAllowed.into();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert_eq!(err.to_string(), "connection not allowed by ruleset");
+    assert_eq!(ReplyError::from_io_error(&io::Error::other("other")), None);
+}
@@ -0,0 +1,241 @@
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::client::Destination;
+
+pub mod codec;
+
+pub use self::codec::{
+    build_reply, build_udp_header, parse_address, parse_udp_header, write_address, Greeting, Reply,
+    ReplyError, Request, CMD_BIND, CMD_CONNECT, CMD_UDP_ASSOCIATE, METHOD_NOT_ACCEPTABLE,
+    METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD, REP_SUCCEEDED, VERSION,
+};
+
+macro_rules! err {
+    ($msg: expr) => {
+        return Err(io::Error::new(ErrorKind::Other, $msg))
+    };
+}
+// (username, password)
+pub type Credentials<'a> = (&'a str, &'a str);
+
+pub async fn handshake<T>(
+    remote: &mut TcpStream,
+    dest: &Destination,
+    data: Option<T>,
+    auth: Option<Credentials<'_>>,
+) -> io::Result<Destination>
+where
+    T: AsRef<[u8]>,
+{
+    // 终于到我最熟悉的socks5协议了
+    // 下面开始socks5握手
+    // https://tools.ietf.org/html/rfc1928#section-3
+    do_handshake(remote, dest, data, auth).await
+}
+async fn do_handshake<T>(
+    remote: &mut TcpStream,
+    dest: &Destination,
+    data: Option<T>,
+    auth: Option<Credentials<'_>>,
+) -> io::Result<Destination>
+where
+    T: AsRef<[u8]>,
+{
+    negotiate(remote, auth).await?;
+    let mut buf = Vec::new();
+    build_request(&mut buf, CMD_CONNECT, dest)?;
+    remote.write_all(&buf).await?;
+
+    // 我竟然给写成这样
+    // 没有分配长度为10的Vec，而是初始化了 [0, 10]
+    // 最后发给client时没将socks connect reply 数据删掉 :(
+    // 还是要善用wireshark的同时抓多网卡的功能，复现问题现场
+    // let mut buf = vec![0, 10];
+    // BND.ADDR 可能是域名或者 ipv6，不能按固定 10 字节读
+    // 返回 upstream 连接目标用的地址，回复给 client
+    let bound = read_reply(remote).await?;
+    // handshake has ended
+    // write out all data from client
+    // pipe started
+    if let Some(data) = data {
+        debug!("Early data has been flushed into socket after finished socks5 handshake");
+        remote.write_all(data.as_ref()).await?;
+    }
+    Ok(bound)
+}
+
+// 作为 client 向 upstream 发起 UDP ASSOCIATE
+// 返回 upstream 用来中转 udp 的地址，remote 需要一直保持连接
+// https://tools.ietf.org/html/rfc1928#section-7
+pub async fn udp_associate(
+    remote: &mut TcpStream,
+    auth: Option<Credentials<'_>>,
+) -> io::Result<Destination> {
+    negotiate(remote, auth).await?;
+    // 我们不知道之后从哪个端口发 udp，按 RFC 填全 0
+    let mut buf = Vec::new();
+    let any: SocketAddr = (Ipv4Addr::UNSPECIFIED, 0).into();
+    build_request(&mut buf, CMD_UDP_ASSOCIATE, &any.into())?;
+    remote.write_all(&buf).await?;
+    read_reply(remote).await
+}
+
+// 作为 client 向 upstream 发起 BIND
+// 返回第一个回复里 upstream 监听的地址，第二个回复用 read_reply 读
+// https://tools.ietf.org/html/rfc1928#section-4
+pub async fn bind(
+    remote: &mut TcpStream,
+    dest: &Destination,
+    auth: Option<Credentials<'_>>,
+) -> io::Result<Destination> {
+    negotiate(remote, auth).await?;
+    let mut buf = Vec::new();
+    build_request(&mut buf, CMD_BIND, dest)?;
+    remote.write_all(&buf).await?;
+    read_reply(remote).await
+}
+
+// 方法协商
+async fn negotiate<S>(remote: &mut S, auth: Option<Credentials<'_>>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // 配置了用户名密码时同时提供 no auth 和 username/password
+    // 由 server 决定用哪个
+    let methods = if auth.is_some() {
+        vec![METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD]
+    } else {
+        vec![METHOD_NO_AUTH]
+    };
+    let mut buf = Vec::with_capacity(4);
+    Greeting { methods }.encode(&mut buf);
+    remote.write_all(&buf).await?;
+    // 只读 2 字节
+    let mut buf = vec![0; 2];
+    remote.read_exact(&mut buf).await?;
+    match (buf[0], buf[1], auth) {
+        (VERSION, METHOD_NO_AUTH, _) => (),
+        (VERSION, METHOD_USERNAME_PASSWORD, Some(auth)) => authenticate(remote, auth).await?,
+        (VERSION, METHOD_NOT_ACCEPTABLE, None) => {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "upstream requires authentication, but no username/password configured",
+            ))
+        }
+        (VERSION, METHOD_NOT_ACCEPTABLE, Some(_)) => {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "upstream doesn't accept username/password authentication",
+            ))
+        }
+        _ => err!(format!("unexpected method selection {:?} from server", buf)),
+    }
+    Ok(())
+}
+
+// 读 upstream 的回复，REP 不是成功时返回 ReplyError
+pub async fn read_reply(remote: &mut TcpStream) -> io::Result<Destination> {
+    Reply::read_from(remote).await?.into_result()
+}
+
+// RFC 1929 的长度字段只有 1 字节，用户名和密码都是 1 到 255 字节
+pub fn validate_credentials(username: &str, password: &str) -> Result<(), &'static str> {
+    if username.is_empty() || username.len() > 255 {
+        return Err("username must be 1 to 255 bytes");
+    }
+    if password.is_empty() || password.len() > 255 {
+        return Err("password must be 1 to 255 bytes");
+    }
+    Ok(())
+}
+
+// https://tools.ietf.org/html/rfc1929
+async fn authenticate<S>(remote: &mut S, (username, password): Credentials<'_>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // +----+------+----------+------+----------+
+    // |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+    // +----+------+----------+------+----------+
+    // | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+    // +----+------+----------+------+----------+
+    let mut buf = Vec::with_capacity(3 + username.len() + password.len());
+    buf.push(0x01);
+    buf.push(username.len() as u8);
+    buf.extend(username.as_bytes());
+    buf.push(password.len() as u8);
+    buf.extend(password.as_bytes());
+    remote.write_all(&buf).await?;
+    // +----+--------+
+    // |VER | STATUS |
+    // +----+--------+
+    // | 1  |   1    |
+    // +----+--------+
+    let mut reply = [0u8; 2];
+    remote.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "upstream rejected username/password authentication for user {}",
+                username
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn build_request(buf: &mut Vec<u8>, cmd: u8, dest: &Destination) -> io::Result<()> {
+    // https://tools.ietf.org/html/rfc1928#section-4
+    Request {
+        command: cmd,
+        dest: dest.clone(),
+    }
+    .encode(buf)
+}
+
+// RFC 1929 子协商，server 依次回复 username/password 方法和状态
+#[tokio::test]
+async fn test_negotiate_username_password() {
+    async fn negotiate_with(method: u8, status: u8) -> (io::Result<()>, Vec<u8>) {
+        let (mut local, mut server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut greeting = [0u8; 4];
+            server.read_exact(&mut greeting).await.unwrap();
+            server.write_all(&[VERSION, method]).await.unwrap();
+            let mut request = Vec::new();
+            if method == METHOD_USERNAME_PASSWORD {
+                let mut buf = [0u8; 14];
+                server.read_exact(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf);
+                server.write_all(&[0x01, status]).await.unwrap();
+            }
+            (greeting, request)
+        });
+        let result = negotiate(&mut local, Some(("alice", "secret"))).await;
+        let (greeting, request) = server.await.unwrap();
+        assert_eq!(
+            greeting,
+            [VERSION, 2, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD]
+        );
+        (result, request)
+    }
+
+    let (result, request) = negotiate_with(METHOD_USERNAME_PASSWORD, 0x00).await;
+    assert!(result.is_ok());
+    assert_eq!(request, b"\x01\x05alice\x06secret");
+
+    let (result, _) = negotiate_with(METHOD_USERNAME_PASSWORD, 0x01).await;
+    let err = result.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert!(err.to_string().contains("alice"), "{}", err);
+
+    let (result, request) = negotiate_with(METHOD_NOT_ACCEPTABLE, 0x00).await;
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    assert!(request.is_empty());
+}
@@ -0,0 +1,117 @@
+// Initial 包的保护用的密钥都从 client 选的第一个 Destination Connection ID 推导出来
+// 任何人都能算出来，所以可以在中间解密 client hello
+// https://www.rfc-editor.org/rfc/rfc9001#section-5.2
+use ring::{
+    aead::{self, quic::HeaderProtectionKey},
+    hkdf,
+};
+
+use super::{QuicError, Version};
+
+pub(super) const CLIENT_IN: &[u8] = b"client in";
+#[cfg(test)]
+pub(super) const SERVER_IN: &[u8] = b"server in";
+
+pub(super) struct InitialKeys {
+    key: aead::LessSafeKey,
+    iv: [u8; aead::NONCE_LEN],
+    hp: HeaderProtectionKey,
+}
+
+impl InitialKeys {
+    // label 是 client in 或者 server in
+    pub(super) fn new(version: Version, dcid: &[u8], label: &[u8]) -> InitialKeys {
+        let initial = hkdf::Salt::new(hkdf::HKDF_SHA256, version.salt()).extract(dcid);
+        let secret = expand_label(&initial, label, 32);
+        let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+        let (key_label, iv_label, hp_label) = version.labels();
+        let key = expand_label(&secret, key_label, 16);
+        let mut iv = [0; aead::NONCE_LEN];
+        iv.copy_from_slice(&expand_label(&secret, iv_label, aead::NONCE_LEN));
+        let hp = expand_label(&secret, hp_label, 16);
+        // 长度是固定的，不会失败
+        InitialKeys {
+            key: aead::LessSafeKey::new(
+                aead::UnboundKey::new(&aead::AES_128_GCM, &key).expect("aes-128-gcm key"),
+            ),
+            iv,
+            hp: HeaderProtectionKey::new(&aead::quic::AES_128, &hp).expect("aes-128 hp key"),
+        }
+    }
+
+    // sample 是 packet number 之后 4 字节开始的 16 字节
+    // https://www.rfc-editor.org/rfc/rfc9001#section-5.4.2
+    pub(super) fn header_mask(&self, sample: &[u8]) -> Result<[u8; 5], QuicError> {
+        self.hp.new_mask(sample).map_err(|_| QuicError::Truncated)
+    }
+
+    // header 是去掉保护之后的，payload 包括最后 16 字节的 tag，返回解密后的明文
+    // https://www.rfc-editor.org/rfc/rfc9001#section-5.3
+    pub(super) fn open<'a>(
+        &self,
+        packet_number: u64,
+        header: &[u8],
+        payload: &'a mut [u8],
+    ) -> Result<&'a mut [u8], QuicError> {
+        let mut nonce = self.iv;
+        for (n, pn) in nonce[4..]
+            .iter_mut()
+            .zip(packet_number.to_be_bytes().iter())
+        {
+            *n ^= pn;
+        }
+        self.key
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(header),
+                payload,
+            )
+            .map_err(|_| QuicError::DecryptFailed)
+    }
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+// HKDF-Expand-Label，context 为空
+// https://tools.ietf.org/html/rfc8446#section-7.1
+pub(super) fn expand_label(secret: &hkdf::Prk, label: &[u8], len: usize) -> Vec<u8> {
+    let out_len = (len as u16).to_be_bytes();
+    let label_len = [(b"tls13 ".len() + label.len()) as u8];
+    let info = [&out_len[..], &label_len, b"tls13 ", label, &[0]];
+    let mut out = vec![0; len];
+    secret
+        .expand(&info, Len(len))
+        .and_then(|okm| okm.fill(&mut out))
+        .expect("hkdf output length");
+    out
+}
+
+#[cfg(test)]
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// https://www.rfc-editor.org/rfc/rfc9001#appendix-A.1
+#[test]
+fn test_initial_keys() {
+    let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    let initial = hkdf::Salt::new(hkdf::HKDF_SHA256, Version::V1.salt()).extract(&dcid);
+    assert_eq!(
+        hex(&expand_label(&initial, CLIENT_IN, 32)),
+        "c00cf151ca5be075ed0ebfb5c80323c42d6b7db67881289af4008f1f6c357aea"
+    );
+    let keys = InitialKeys::new(Version::V1, &dcid, CLIENT_IN);
+    assert_eq!(hex(&keys.iv), "fa044b2f42a3fd3b46fb255c");
+    // A.2 里的 sample 和 mask
+    let sample = [
+        0xd1, 0xb1, 0xc9, 0x8d, 0xd7, 0x68, 0x9f, 0xb8, 0xec, 0x11, 0xd2, 0x42, 0xb1, 0x23, 0xdc,
+        0x9b,
+    ];
+    assert_eq!(hex(&keys.header_mask(&sample).unwrap()), "437b9aec36");
+}
@@ -0,0 +1,500 @@
+// 从 QUIC v1/v2 的 Initial 包里取出 client hello
+// 去掉 header protection，解密 payload，按 offset 拼起 CRYPTO 帧，再交给 tls 解析
+// https://www.rfc-editor.org/rfc/rfc9000#section-17.2.2
+// https://www.rfc-editor.org/rfc/rfc9001#section-5
+// https://www.rfc-editor.org/rfc/rfc9369
+use std::{collections::BTreeMap, error, fmt};
+
+use crate::tls::{self, TlsClientHello, TlsError};
+
+mod crypto;
+
+use self::crypto::{InitialKeys, CLIENT_IN};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    fn from_u32(version: u32) -> Option<Version> {
+        match version {
+            0x0000_0001 => Some(Version::V1),
+            0x6b33_43cf => Some(Version::V2),
+            _ => None,
+        }
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc9001#section-5.2
+    // https://www.rfc-editor.org/rfc/rfc9369#section-3.3.1
+    fn salt(self) -> &'static [u8] {
+        match self {
+            Version::V1 => &[
+                0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8,
+                0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+            ],
+            Version::V2 => &[
+                0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26,
+                0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+            ],
+        }
+    }
+
+    // key、iv、hp 的 HKDF label
+    // https://www.rfc-editor.org/rfc/rfc9369#section-3.3.2
+    fn labels(self) -> (&'static [u8], &'static [u8], &'static [u8]) {
+        match self {
+            Version::V1 => (b"quic key", b"quic iv", b"quic hp"),
+            Version::V2 => (b"quicv2 key", b"quicv2 iv", b"quicv2 hp"),
+        }
+    }
+
+    // long header 里 Initial 的 type，v2 换了编号
+    // https://www.rfc-editor.org/rfc/rfc9369#section-3.2
+    fn initial_type(self) -> u8 {
+        match self {
+            Version::V1 => 0b00,
+            Version::V2 => 0b01,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuicError {
+    // 不是 long header 的 Initial 包
+    NotInitial,
+    UnsupportedVersion,
+    // 数据不完整，或者某个长度超出了数据
+    Truncated,
+    // Initial 包里不允许的帧，或者帧的格式不对
+    InvalidFrame,
+    // 认证失败，一般说明不是 client 发的第一个 Initial
+    DecryptFailed,
+    // 拼出的 client hello 解析失败
+    Tls(TlsError),
+}
+
+impl fmt::Display for QuicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuicError::NotInitial => f.write_str("not a quic initial packet"),
+            QuicError::UnsupportedVersion => f.write_str("unsupported quic version"),
+            QuicError::Truncated => f.write_str("truncated quic packet"),
+            QuicError::InvalidFrame => f.write_str("invalid frame in quic initial packet"),
+            QuicError::DecryptFailed => f.write_str("failed to decrypt quic initial packet"),
+            QuicError::Tls(err) => write!(f, "quic client hello: {}", err),
+        }
+    }
+}
+
+impl error::Error for QuicError {}
+
+impl From<TlsError> for QuicError {
+    fn from(err: TlsError) -> Self {
+        QuicError::Tls(err)
+    }
+}
+
+// client hello 超过一个包时（比如带了抗量子的 key share）分在多个 Initial 里
+// 同一个连接的包依次 push，拼出完整的 client hello 为止
+#[derive(Default)]
+pub struct InitialAssembler {
+    // 第一个包的版本和用它的 DCID 推导的密钥，后面的包都用这个
+    keys: Option<(Version, InitialKeys)>,
+    // CRYPTO 帧的 offset -> 数据
+    crypto: BTreeMap<u64, Vec<u8>>,
+    len: usize,
+}
+
+impl InitialAssembler {
+    // 数据还不够时返回 Ok(None)
+    // 一个 udp 包里可以有多个合在一起的 QUIC 包，只处理其中的 Initial
+    pub fn push(&mut self, datagram: &[u8]) -> Result<Option<TlsClientHello>, QuicError> {
+        let mut rest = datagram;
+        let mut initial = false;
+        // 后面的填充或者 short header 包不再看
+        while rest.first().is_some_and(|&b| b & 0xc0 == 0xc0) {
+            let packet = LongHeader::parse(rest)?;
+            let version = match self.keys {
+                Some((version, _)) => version,
+                None => packet.version,
+            };
+            if packet.version == version && packet.packet_type == version.initial_type() {
+                let keys = &self
+                    .keys
+                    .get_or_insert_with(|| {
+                        (version, InitialKeys::new(version, packet.dcid, CLIENT_IN))
+                    })
+                    .1;
+                let payload = decrypt(&rest[..packet.end], packet.pn_offset, keys)?;
+                for (offset, data) in crypto_frames(&payload)? {
+                    self.insert(offset, data)?;
+                }
+                initial = true;
+            }
+            rest = &rest[packet.end..];
+        }
+        if !initial {
+            return Err(QuicError::NotInitial);
+        }
+        self.client_hello()
+    }
+
+    fn insert(&mut self, offset: u64, data: &[u8]) -> Result<(), QuicError> {
+        if offset as usize + data.len() > tls::MAX_HANDSHAKE_LEN {
+            return Err(TlsError::TooLong.into());
+        }
+        // 重传的帧 offset 相同，保留长的那个
+        let entry = self.crypto.entry(offset).or_default();
+        if data.len() > entry.len() {
+            self.len += data.len() - entry.len();
+            *entry = data.to_vec();
+        }
+        if self.len > tls::MAX_HANDSHAKE_LEN {
+            return Err(TlsError::TooLong.into());
+        }
+        Ok(())
+    }
+
+    // 从 offset 0 开始连续的数据够一个握手消息时解析
+    fn client_hello(&self) -> Result<Option<TlsClientHello>, QuicError> {
+        let mut message = Vec::new();
+        for (&offset, data) in self.crypto.iter() {
+            let offset = offset as usize;
+            if offset > message.len() {
+                break;
+            }
+            if offset + data.len() > message.len() {
+                message.extend_from_slice(&data[message.len() - offset..]);
+            }
+        }
+        match tls::check_handshake(&message)? {
+            Some(total) if message.len() >= total => {
+                Ok(Some(tls::parse_handshake(&message[..total])?))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+// 只有一个 udp 包时用，client hello 不完整时返回 Truncated
+pub fn parse_initial(datagram: &[u8]) -> Result<TlsClientHello, QuicError> {
+    InitialAssembler::default()
+        .push(datagram)?
+        .ok_or(QuicError::Truncated)
+}
+
+// 粗略判断是不是 QUIC v1/v2 的 Initial，不解密
+pub fn is_initial(datagram: &[u8]) -> bool {
+    match datagram {
+        [first, v0, v1, v2, v3, ..] if first & 0xc0 == 0xc0 => {
+            Version::from_u32(u32::from_be_bytes([*v0, *v1, *v2, *v3]))
+                .is_some_and(|version| (first >> 4) & 0x03 == version.initial_type())
+        }
+        _ => false,
+    }
+}
+
+// Long Header Packet {
+//   Header Form (1) = 1, Fixed Bit (1) = 1, Long Packet Type (2),
+//   Type-Specific Bits (4), Version (32),
+//   Destination Connection ID Length (8), Destination Connection ID (0..160),
+//   Source Connection ID Length (8), Source Connection ID (0..160),
+//   Initial 时 Token Length (i), Token (..),
+//   Length (i), Packet Number (8..32), Packet Payload (..)
+// }
+// https://www.rfc-editor.org/rfc/rfc9000#section-17.2
+struct LongHeader<'a> {
+    version: Version,
+    packet_type: u8,
+    dcid: &'a [u8],
+    // packet number 开始的位置
+    pn_offset: usize,
+    // 这个 QUIC 包结束的位置
+    end: usize,
+}
+
+impl<'a> LongHeader<'a> {
+    fn parse(data: &'a [u8]) -> Result<LongHeader<'a>, QuicError> {
+        let (first, version) = match *data {
+            [first, v0, v1, v2, v3, ..] => (first, u32::from_be_bytes([v0, v1, v2, v3])),
+            _ => return Err(QuicError::Truncated),
+        };
+        let version = Version::from_u32(version).ok_or(QuicError::UnsupportedVersion)?;
+        let packet_type = (first >> 4) & 0x03;
+        let mut rest = &data[5..];
+        let dcid = read_bytes(&mut rest, 1)?;
+        let scid = read_bytes(&mut rest, 1)?;
+        if dcid.len() > 20 || scid.len() > 20 {
+            return Err(QuicError::NotInitial);
+        }
+        // Retry 没有 Length，client 也不会发
+        if packet_type == version.initial_type() {
+            let token_len = read_varint(&mut rest)?;
+            rest = rest.get(token_len as usize..).ok_or(QuicError::Truncated)?;
+        }
+        let len = read_varint(&mut rest)? as usize;
+        let pn_offset = data.len() - rest.len();
+        if rest.len() < len {
+            return Err(QuicError::Truncated);
+        }
+        Ok(LongHeader {
+            version,
+            packet_type,
+            dcid,
+            pn_offset,
+            end: pn_offset + len,
+        })
+    }
+}
+
+// packet 是一个完整的 Initial 包，返回解密后的 payload
+fn decrypt(packet: &[u8], pn_offset: usize, keys: &InitialKeys) -> Result<Vec<u8>, QuicError> {
+    // 不管 packet number 多长，都从第 4 个字节开始取 sample
+    let sample = packet
+        .get(pn_offset + 4..pn_offset + 20)
+        .ok_or(QuicError::Truncated)?;
+    let mask = keys.header_mask(sample)?;
+    let mut packet = packet.to_vec();
+    packet[0] ^= mask[0] & 0x0f;
+    let pn_len = (packet[0] & 0x03) as usize + 1;
+    let mut packet_number = 0u64;
+    for i in 0..pn_len {
+        packet[pn_offset + i] ^= mask[1 + i];
+        packet_number = packet_number << 8 | packet[pn_offset + i] as u64;
+    }
+    // client 开始的几个 Initial 的 packet number 很小，截断的值就是完整的值
+    let (header, payload) = packet.split_at_mut(pn_offset + pn_len);
+    let plain = keys.open(packet_number, header, payload)?;
+    Ok(plain.to_vec())
+}
+
+// Initial 里只能有 PADDING、PING、ACK、CRYPTO 和 CONNECTION_CLOSE
+// https://www.rfc-editor.org/rfc/rfc9000#section-12.4
+fn crypto_frames(payload: &[u8]) -> Result<Vec<(u64, &[u8])>, QuicError> {
+    let mut frames = Vec::new();
+    let mut rest = payload;
+    while let Some((&frame_type, tail)) = rest.split_first() {
+        rest = tail;
+        match frame_type {
+            // PADDING、PING
+            0x00 | 0x01 => (),
+            // ACK，0x03 多了 3 个 ECN 计数
+            0x02 | 0x03 => {
+                // Largest Acknowledged、ACK Delay
+                read_varint(&mut rest)?;
+                read_varint(&mut rest)?;
+                let ranges = read_varint(&mut rest)?;
+                // First ACK Range
+                read_varint(&mut rest)?;
+                // 每个 range 有 Gap 和 ACK Range Length
+                for _ in 0..ranges {
+                    read_varint(&mut rest)?;
+                    read_varint(&mut rest)?;
+                }
+                if frame_type == 0x03 {
+                    for _ in 0..3 {
+                        read_varint(&mut rest)?;
+                    }
+                }
+            }
+            // CRYPTO，Offset (i)、Length (i)、Crypto Data
+            0x06 => {
+                let offset = read_varint(&mut rest)?;
+                let len = read_varint(&mut rest)? as usize;
+                let data = rest.get(..len).ok_or(QuicError::Truncated)?;
+                rest = &rest[len..];
+                frames.push((offset, data));
+            }
+            // CONNECTION_CLOSE，Error Code (i)、Frame Type (i)、Reason Phrase
+            0x1c => {
+                read_varint(&mut rest)?;
+                read_varint(&mut rest)?;
+                let len = read_varint(&mut rest)? as usize;
+                rest = rest.get(len..).ok_or(QuicError::Truncated)?;
+            }
+            _ => return Err(QuicError::InvalidFrame),
+        }
+    }
+    Ok(frames)
+}
+
+// 前两位是长度，1、2、4、8 字节
+// https://www.rfc-editor.org/rfc/rfc9000#section-16
+fn read_varint(data: &mut &[u8]) -> Result<u64, QuicError> {
+    let first = *data.first().ok_or(QuicError::Truncated)?;
+    let len = 1 << (first >> 6);
+    let bytes = data.get(..len).ok_or(QuicError::Truncated)?;
+    let mut value = (first & 0x3f) as u64;
+    for &b in &bytes[1..] {
+        value = value << 8 | b as u64;
+    }
+    *data = &data[len..];
+    Ok(value)
+}
+
+// len_size 个字节的长度，后面跟着数据
+fn read_bytes<'a>(data: &mut &'a [u8], len_size: usize) -> Result<&'a [u8], QuicError> {
+    let len = data
+        .get(..len_size)
+        .ok_or(QuicError::Truncated)?
+        .iter()
+        .fold(0usize, |len, &b| len << 8 | b as usize);
+    let bytes = data
+        .get(len_size..len_size + len)
+        .ok_or(QuicError::Truncated)?;
+    *data = &data[len_size + len..];
+    Ok(bytes)
+}
+
+// RFC 9001 附录 A.2 的 client Initial
+#[cfg(test)]
+const RFC9001_CLIENT_INITIAL: &[u8] = include_bytes!("testdata/rfc9001-client-initial.bin");
+
+#[test]
+fn test_parse_initial() {
+    assert!(is_initial(RFC9001_CLIENT_INITIAL));
+    let hello = parse_initial(RFC9001_CLIENT_INITIAL).unwrap();
+    assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+    let alpn: Vec<&str> = hello.alpn.iter().map(|a| &**a).collect();
+    assert_eq!(alpn, ["alpn"]);
+    assert_eq!(hello.cipher_suites, [0x1301, 0x1302]);
+    assert_eq!(hello.supported_versions, [0x0304]);
+    assert_eq!(hello.key_share_groups, [0x001d]);
+    assert!(hello.ja4_quic().starts_with("q13d0211an_"));
+
+    // 任意位置截断都不能 panic
+    for len in 0..RFC9001_CLIENT_INITIAL.len() {
+        assert!(parse_initial(&RFC9001_CLIENT_INITIAL[..len]).is_err());
+    }
+    // 改一个字节认证失败
+    let mut packet = RFC9001_CLIENT_INITIAL.to_vec();
+    packet[100] ^= 1;
+    assert_eq!(parse_initial(&packet).err(), Some(QuicError::DecryptFailed));
+    // 后面跟着 short header 或者填充不影响
+    let mut packet = RFC9001_CLIENT_INITIAL.to_vec();
+    packet.extend_from_slice(&[0x40, 0x01, 0x02]);
+    assert!(parse_initial(&packet).is_ok());
+
+    let mut packet = RFC9001_CLIENT_INITIAL.to_vec();
+    packet[1..5].copy_from_slice(&[0xff, 0x00, 0x00, 0x1d]);
+    assert!(!is_initial(&packet));
+    assert_eq!(
+        parse_initial(&packet).err(),
+        Some(QuicError::UnsupportedVersion)
+    );
+    assert_eq!(
+        parse_initial(b"\x40\x01").err(),
+        Some(QuicError::NotInitial)
+    );
+    assert_eq!(parse_initial(b"").err(), Some(QuicError::NotInitial));
+}
+
+// RFC 9369 附录 A.2 的 client Initial：RFC 9001 A.2 的明文换成 v2 的头和密钥
+// 离线拿不到 RFC 原文，用 rustls 0.23.45 的 QUIC v2 Initial 密钥生成
+#[test]
+fn test_parse_initial_v2() {
+    let data: &[u8] = include_bytes!("testdata/rfc9369-client-initial.bin");
+    assert_eq!(data.len(), 1200);
+    assert_eq!(data[1..5], [0x6b, 0x33, 0x43, 0xcf]);
+    assert!(is_initial(data));
+    let hello = parse_initial(data).unwrap();
+    assert_eq!(hello, parse_initial(RFC9001_CLIENT_INITIAL).unwrap());
+    assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+    // v1 的 Initial type 是 0，v2 是 1，头里的 type 不对时不认
+    let mut packet = data.to_vec();
+    packet[1..5].copy_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+    assert!(!is_initial(&packet));
+    for len in 0..data.len() {
+        assert!(parse_initial(&data[..len]).is_err());
+    }
+}
+
+// RFC 9369 附录 A.3 的 server Initial，payload 是 ACK 和 server hello 的 CRYPTO 帧
+// 这里只能用 server 的密钥解开，验证 v2 的 salt、label 和 type
+#[test]
+fn test_decrypt_v2() {
+    let data = include_bytes!("testdata/rfc9369-server-initial.bin");
+    let packet = LongHeader::parse(data).unwrap();
+    assert_eq!(packet.version, Version::V2);
+    assert_eq!(packet.packet_type, Version::V2.initial_type());
+    assert_eq!(packet.end, data.len());
+    let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    let keys = InitialKeys::new(Version::V2, &dcid, crypto::SERVER_IN);
+    let payload = decrypt(data, packet.pn_offset, &keys).unwrap();
+    let frames = crypto_frames(&payload).unwrap();
+    assert_eq!(frames.len(), 1);
+    let (offset, hello) = frames[0];
+    assert_eq!((offset, hello.len()), (0, 90));
+    // ServerHello
+    assert_eq!(tls::check_handshake(hello), Err(TlsError::NotClientHello));
+    assert_eq!(hello[..4], [0x02, 0x00, 0x00, 0x56]);
+
+    // 同样的 server 密钥在 v1 下解不开
+    let keys = InitialKeys::new(Version::V1, &dcid, crypto::SERVER_IN);
+    assert!(decrypt(data, packet.pn_offset, &keys).is_err());
+}
+
+// client hello 分在两个 udp 包里，CRYPTO 帧乱序
+#[test]
+fn test_initial_assembler() {
+    let packet = LongHeader::parse(RFC9001_CLIENT_INITIAL).unwrap();
+    let keys = InitialKeys::new(Version::V1, packet.dcid, CLIENT_IN);
+    let payload = decrypt(RFC9001_CLIENT_INITIAL, packet.pn_offset, &keys).unwrap();
+    let (_, hello) = crypto_frames(&payload).unwrap()[0];
+    let mut assembler = InitialAssembler::default();
+    // 只有后半部分
+    let datagram = seal_initial(packet.dcid, 3, &[(100, &hello[100..])]);
+    assert_eq!(assembler.push(&datagram).unwrap(), None);
+    // 重复的帧不影响
+    assert_eq!(assembler.push(&datagram).unwrap(), None);
+    let datagram = seal_initial(packet.dcid, 4, &[(40, &hello[40..120]), (0, &hello[..50])]);
+    let hello = assembler.push(&datagram).unwrap().unwrap();
+    assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+}
+
+// 测试用，按 RFC 9001 的方式加密一个只有 CRYPTO 帧的 v1 client Initial
+#[cfg(test)]
+fn seal_initial(dcid: &[u8], packet_number: u8, frames: &[(u64, &[u8])]) -> Vec<u8> {
+    use ring::aead;
+    let mut payload = Vec::new();
+    for (offset, data) in frames {
+        // 都用 8 字节和 2 字节的 varint
+        payload.push(0x06);
+        payload.extend_from_slice(&(0xc000_0000_0000_0000 | offset).to_be_bytes());
+        payload.extend_from_slice(&(0x4000 | data.len() as u16).to_be_bytes());
+        payload.extend_from_slice(data);
+    }
+    payload.resize(payload.len().max(32), 0);
+    // 1 字节的 packet number，2 字节的 Length
+    let mut packet = vec![0xc0, 0x00, 0x00, 0x00, 0x01, dcid.len() as u8];
+    packet.extend_from_slice(dcid);
+    packet.extend_from_slice(&[0x00, 0x00]);
+    packet.extend_from_slice(&(0x4000 | (1 + payload.len() + 16) as u16).to_be_bytes());
+    let pn_offset = packet.len();
+    packet.push(packet_number);
+
+    let initial = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, Version::V1.salt()).extract(dcid);
+    let secret = crypto::expand_label(&initial, CLIENT_IN, 32);
+    let secret = ring::hkdf::Prk::new_less_safe(ring::hkdf::HKDF_SHA256, &secret);
+    let key = crypto::expand_label(&secret, b"quic key", 16);
+    let iv = crypto::expand_label(&secret, b"quic iv", 12);
+    let hp = crypto::expand_label(&secret, b"quic hp", 16);
+    let key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, &key).unwrap());
+    let mut nonce = [0; 12];
+    nonce.copy_from_slice(&iv);
+    nonce[11] ^= packet_number;
+    key.seal_in_place_append_tag(
+        aead::Nonce::assume_unique_for_key(nonce),
+        aead::Aad::from(&packet[..]),
+        &mut payload,
+    )
+    .unwrap();
+    packet.extend_from_slice(&payload);
+    let hp = aead::quic::HeaderProtectionKey::new(&aead::quic::AES_128, &hp).unwrap();
+    let mask = hp.new_mask(&packet[pn_offset + 4..pn_offset + 20]).unwrap();
+    packet[0] ^= mask[0] & 0x0f;
+    packet[pn_offset] ^= mask[1];
+    packet
+}
@@ -0,0 +1,361 @@
+// v2ray 格式的 geoip.dat 和 geosite.dat，规则里用 geoip:cn、geosite:cn 引用
+// 文件是 protobuf 编码的 GeoIPList、GeoSiteList
+// https://github.com/v2fly/v2ray-core/blob/master/app/router/routercommon/common.proto
+// 文件里有几百个 code，只解析规则用到的
+use std::{collections::HashMap, convert::TryFrom, fs, io, net::IpAddr, path::Path};
+
+use log::info;
+
+use super::{provider::Provider, Cidr};
+
+pub const GEOIP_PREFIX: &str = "geoip:";
+pub const GEOSITE_PREFIX: &str = "geosite:";
+
+// Domain.Type
+const DOMAIN_PLAIN: u64 = 0;
+const DOMAIN_REGEX: u64 = 1;
+const DOMAIN_ROOT: u64 = 2;
+const DOMAIN_FULL: u64 = 3;
+
+// names 是规则里写的 geoip:cn
+pub fn load_geoip(path: &Path, names: &[&str]) -> io::Result<Vec<Provider>> {
+    load(path, names, parse_geoip)
+}
+
+// names 是规则里写的 geosite:cn，可以用 @ 只选带某个 attribute 的域名，geosite:google@cn
+pub fn load_geosite(path: &Path, names: &[&str]) -> io::Result<Vec<Provider>> {
+    load(path, names, parse_geosite)
+}
+
+fn load<F>(path: &Path, names: &[&str], parse: F) -> io::Result<Vec<Provider>>
+where
+    F: FnOnce(&[u8], &[&str]) -> Result<Vec<Provider>, String>,
+{
+    let data = fs::read(path).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("failed to read {}: {}", path.display(), err),
+        )
+    })?;
+    let providers = parse(&data, names).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), err),
+        )
+    })?;
+    for provider in providers.iter() {
+        info!(
+            "{} loaded {} entries from {}",
+            provider.name,
+            provider.len(),
+            path.display()
+        );
+    }
+    Ok(providers)
+}
+
+// message GeoIPList { repeated GeoIP entry = 1; }
+// message GeoIP { string country_code = 1; repeated CIDR cidr = 2; bool reverse_match = 3; }
+// message CIDR { bytes ip = 1; uint32 prefix = 2; }
+pub fn parse_geoip(data: &[u8], names: &[&str]) -> Result<Vec<Provider>, String> {
+    let codes = names
+        .iter()
+        .map(|name| strip_prefix(name, GEOIP_PREFIX))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut found: HashMap<usize, Provider> = HashMap::new();
+    for (code, fields) in entries(data)? {
+        for (i, _) in codes
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.eq_ignore_ascii_case(code))
+        {
+            let provider = found.entry(i).or_insert_with(|| Provider::new(names[i]));
+            for field in Reader::new(fields) {
+                match field? {
+                    (2, Value::Bytes(cidr)) => provider.insert_cidr(parse_cidr(cidr)?),
+                    (3, Value::Varint(reverse)) => provider.set_reverse_ips(reverse != 0),
+                    _ => (),
+                }
+            }
+        }
+    }
+    collect(names, found)
+}
+
+fn parse_cidr(data: &[u8]) -> Result<Cidr, String> {
+    let mut ip = None;
+    let mut prefix = 0;
+    for field in Reader::new(data) {
+        match field? {
+            (1, Value::Bytes(bytes)) => ip = Some(bytes),
+            (2, Value::Varint(value)) => prefix = value,
+            _ => (),
+        }
+    }
+    let addr = match ip {
+        Some(bytes) if bytes.len() == 4 => IpAddr::from(<[u8; 4]>::try_from(bytes).unwrap()),
+        Some(bytes) if bytes.len() == 16 => IpAddr::from(<[u8; 16]>::try_from(bytes).unwrap()),
+        _ => return Err(String::from("invalid cidr ip")),
+    };
+    let prefix = u8::try_from(prefix).map_err(|_| format!("invalid prefix length {}", prefix))?;
+    Cidr::new(addr, prefix)
+}
+
+// message GeoSiteList { repeated GeoSite entry = 1; }
+// message GeoSite { string country_code = 1; repeated Domain domain = 2; }
+// message Domain { Type type = 1; string value = 2; repeated Attribute attribute = 3; }
+// message Attribute { string key = 1; oneof typed_value { bool bool_value = 2; int64 int_value = 3; } }
+pub fn parse_geosite(data: &[u8], names: &[&str]) -> Result<Vec<Provider>, String> {
+    // cn@ads@foo -> (cn, [ads, foo])
+    let selectors = names
+        .iter()
+        .map(|name| {
+            let mut parts = strip_prefix(name, GEOSITE_PREFIX)?.split('@');
+            let code = parts.next().unwrap_or_default();
+            Ok((code, parts.collect::<Vec<_>>()))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let mut found: HashMap<usize, (Provider, Vec<String>)> = HashMap::new();
+    for (code, fields) in entries(data)? {
+        let matched: Vec<usize> = selectors
+            .iter()
+            .enumerate()
+            .filter(|(_, (c, _))| c.eq_ignore_ascii_case(code))
+            .map(|(i, _)| i)
+            .collect();
+        if matched.is_empty() {
+            continue;
+        }
+        for field in Reader::new(fields) {
+            let domain = match field? {
+                (2, Value::Bytes(domain)) => parse_domain(domain)?,
+                _ => continue,
+            };
+            for &i in matched.iter() {
+                let attrs = &selectors[i].1;
+                if !attrs
+                    .iter()
+                    .all(|attr| domain.attrs.iter().any(|a| a.eq_ignore_ascii_case(attr)))
+                {
+                    continue;
+                }
+                let (provider, regex) = found
+                    .entry(i)
+                    .or_insert_with(|| (Provider::new(names[i]), Vec::new()));
+                match domain.kind {
+                    DOMAIN_PLAIN => provider.insert_keyword(domain.value),
+                    DOMAIN_REGEX => regex.push(String::from(domain.value)),
+                    DOMAIN_ROOT => provider.insert_suffix(domain.value),
+                    DOMAIN_FULL => provider.insert_full(domain.value),
+                    kind => return Err(format!("unknown domain type {}", kind)),
+                }
+            }
+        }
+    }
+    let mut providers = HashMap::new();
+    for (i, (mut provider, regex)) in found {
+        provider
+            .set_regex(&regex)
+            .map_err(|err| format!("{}: {}", names[i], err))?;
+        providers.insert(i, provider);
+    }
+    collect(names, providers)
+}
+
+struct Domain<'a> {
+    kind: u64,
+    value: &'a str,
+    attrs: Vec<&'a str>,
+}
+
+fn parse_domain(data: &[u8]) -> Result<Domain<'_>, String> {
+    // proto3 里等于默认值的字段不会编码，type 没有就是 Plain
+    let mut domain = Domain {
+        kind: DOMAIN_PLAIN,
+        value: "",
+        attrs: Vec::new(),
+    };
+    for field in Reader::new(data) {
+        match field? {
+            (1, Value::Varint(kind)) => domain.kind = kind,
+            (2, Value::Bytes(value)) => domain.value = to_str(value)?,
+            (3, Value::Bytes(attr)) => {
+                for field in Reader::new(attr) {
+                    if let (1, Value::Bytes(key)) = field? {
+                        domain.attrs.push(to_str(key)?);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(domain)
+}
+
+// GeoIPList 和 GeoSiteList 的结构一样，返回每个 entry 的 country_code 和整个 entry
+fn entries(data: &[u8]) -> Result<Vec<(&str, &[u8])>, String> {
+    let mut entries = Vec::new();
+    for field in Reader::new(data) {
+        let entry = match field? {
+            (1, Value::Bytes(entry)) => entry,
+            _ => continue,
+        };
+        let mut code = "";
+        for field in Reader::new(entry) {
+            if let (1, Value::Bytes(value)) = field? {
+                code = to_str(value)?;
+            }
+        }
+        entries.push((code, entry));
+    }
+    Ok(entries)
+}
+
+// 每个 name 都要找到，不然规则永远不会命中
+fn collect(names: &[&str], mut found: HashMap<usize, Provider>) -> Result<Vec<Provider>, String> {
+    (0..names.len())
+        .map(|i| {
+            found
+                .remove(&i)
+                .ok_or_else(|| format!("{} not found", names[i]))
+        })
+        .collect()
+}
+
+fn strip_prefix<'a>(name: &'a str, prefix: &str) -> Result<&'a str, String> {
+    match name.strip_prefix(prefix) {
+        Some(code) if !code.is_empty() => Ok(code),
+        _ => Err(format!("invalid name {}", name)),
+    }
+}
+
+fn to_str(data: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(data).map_err(|_| String::from("invalid utf-8 string"))
+}
+
+// 只实现用到的 wire type
+// https://developers.google.com/protocol-buffers/docs/encoding
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for i in 0..10 {
+            let (&byte, rest) = self
+                .buf
+                .split_first()
+                .ok_or_else(|| String::from("truncated varint"))?;
+            self.buf = rest;
+            value |= u64::from(byte & 0x7f) << (i * 7);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(String::from("varint too long"))
+    }
+    fn take(&mut self, len: u64) -> Result<&'a [u8], String> {
+        if len > self.buf.len() as u64 {
+            return Err(String::from("truncated field"));
+        }
+        let (value, rest) = self.buf.split_at(len as usize);
+        self.buf = rest;
+        Ok(value)
+    }
+    fn field(&mut self) -> Result<(u64, Value<'a>), String> {
+        let key = self.varint()?;
+        let value = match key & 0x07 {
+            0 => Value::Varint(self.varint()?),
+            1 => self.take(8).map(|_| Value::Fixed)?,
+            2 => {
+                let len = self.varint()?;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => self.take(4).map(|_| Value::Fixed)?,
+            wire_type => return Err(format!("unsupported wire type {}", wire_type)),
+        };
+        Ok((key >> 3, value))
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Result<(u64, Value<'a>), String>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            // 出错后不再继续
+            self.buf = &[];
+        }
+        Some(field)
+    }
+}
+
+#[cfg(test)]
+const GEOIP_FIXTURE: &[u8] = include_bytes!("testdata/geoip.dat");
+#[cfg(test)]
+const GEOSITE_FIXTURE: &[u8] = include_bytes!("testdata/geosite.dat");
+
+// testdata/geoip.dat:
+//   CN: 1.0.1.0/24, 240e::/20
+//   PRIVATE: 10.0.0.0/8, 192.168.0.0/16, fc00::/7
+//   NOTCN: 1.0.1.0/24, reverse_match
+#[test]
+fn test_parse_geoip() {
+    let providers =
+        parse_geoip(GEOIP_FIXTURE, &["geoip:cn", "geoip:private", "geoip:notcn"]).unwrap();
+    let [cn, private, not_cn] = <[Provider; 3]>::try_from(providers).unwrap();
+    assert_eq!(cn.name, "geoip:cn");
+    assert!(cn.contains_ip("1.0.1.1".parse().unwrap()));
+    assert!(cn.contains_ip("240e:3b0::1".parse().unwrap()));
+    assert!(!cn.contains_ip("8.8.8.8".parse().unwrap()));
+    assert!(private.contains_ip("192.168.3.4".parse().unwrap()));
+    assert!(private.contains_ip("fd00::1".parse().unwrap()));
+    assert!(!private.contains_ip("1.0.1.1".parse().unwrap()));
+    assert!(!not_cn.contains_ip("1.0.1.1".parse().unwrap()));
+    assert!(not_cn.contains_ip("8.8.8.8".parse().unwrap()));
+
+    let err = parse_geoip(GEOIP_FIXTURE, &["geoip:us"]).unwrap_err();
+    assert!(err.contains("geoip:us not found"), "{}", err);
+    assert!(parse_geoip(&GEOIP_FIXTURE[..GEOIP_FIXTURE.len() - 1], &["geoip:cn"]).is_err());
+}
+
+// testdata/geosite.dat:
+//   CN: Domain baidu.cn, Full www.qq.com, Plain taobao, Regex ^cdn[0-9]+\.jd\.com$
+//   GOOGLE: Domain google.com, Domain google.cn @cn, Full ads.google.com @ads
+#[test]
+fn test_parse_geosite() {
+    let providers = parse_geosite(
+        GEOSITE_FIXTURE,
+        &["geosite:cn", "geosite:google", "geosite:google@cn"],
+    )
+    .unwrap();
+    let [cn, google, google_cn] = <[Provider; 3]>::try_from(providers).unwrap();
+    assert!(cn.contains_domain("www.baidu.cn"));
+    assert!(cn.contains_domain("www.qq.com"));
+    assert!(!cn.contains_domain("a.www.qq.com"));
+    assert!(cn.contains_domain("world.taobao.com"));
+    assert!(cn.contains_domain("cdn12.jd.com"));
+    assert!(!cn.contains_domain("cdn.jd.com"));
+    assert!(google.contains_domain("mail.google.com"));
+    assert!(google.contains_domain("ads.google.com"));
+    assert!(google.contains_domain("google.cn"));
+    assert!(google_cn.contains_domain("www.google.cn"));
+    assert!(!google_cn.contains_domain("mail.google.com"));
+    assert_eq!(google_cn.len(), 1);
+
+    let err = parse_geosite(GEOSITE_FIXTURE, &["geosite:google@ir"]).unwrap_err();
+    assert!(err.contains("not found"), "{}", err);
+}
@@ -0,0 +1,521 @@
+// 按配置的路由规则给每个连接选择 outbound
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    config::{Config, ECH_IP, ECH_SNI, OUTBOUND_DIRECT, OUTBOUND_PROXY, OUTBOUND_REJECT},
+    sniff::Sniffed,
+    tls::Ech,
+};
+
+mod geo;
+mod provider;
+mod rule;
+mod trie;
+
+pub use self::provider::Provider;
+use self::rule::normalize_domain;
+pub use self::rule::{Cidr, PortRange, Rule, Session};
+pub use self::trie::{DomainTrie, IpTrie};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outbound {
+    // 按 upstream_policy 使用所有 upstream
+    Proxy,
+    Direct,
+    // 直接拒绝，回复 client connection not allowed
+    Reject,
+    // 指定名字的 upstream，config.upstreams 的下标
+    Upstream(usize),
+}
+
+// 命中的规则，日志用
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Matched {
+    // config.routing.rules 的下标
+    Rule(usize),
+    Final,
+    // routing.ech 指定了 outbound
+    Ech,
+}
+
+// routing.ech，带 ECH 的 tls 连接怎么处理嗅探出的 outer SNI
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EchPolicy {
+    Sni,
+    Ip,
+    Outbound(Outbound),
+}
+
+#[derive(Debug)]
+pub struct Router {
+    rules: Vec<Rule>,
+    final_outbound: Outbound,
+    ech: EchPolicy,
+    // routing.ech_public_names
+    ech_public_names: DomainTrie,
+}
+
+impl Default for Router {
+    // 没有规则，全部走 upstream
+    fn default() -> Self {
+        Router {
+            rules: Vec::new(),
+            final_outbound: Outbound::Proxy,
+            ech: EchPolicy::Sni,
+            ech_public_names: DomainTrie::default(),
+        }
+    }
+}
+
+impl Router {
+    pub fn new(config: &Config) -> io::Result<Router> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut providers = HashMap::new();
+        for (name, provider) in config.routing.providers.iter() {
+            // : 留给 geoip: 和 geosite:
+            if name.contains(':') {
+                return Err(invalid(format!("routing provider {}: invalid name", name)));
+            }
+            let provider = Provider::load(name, provider).map_err(|err| {
+                io::Error::new(err.kind(), format!("routing provider {}: {}", name, err))
+            })?;
+            providers.insert(name.clone(), Arc::new(provider));
+        }
+        for provider in load_geo(config)? {
+            providers.insert(provider.name.clone(), Arc::new(provider));
+        }
+        let mut rules = Vec::with_capacity(config.routing.rules.len());
+        for (i, rule) in config.routing.rules.iter().enumerate() {
+            let rule = resolve_outbound(config, &rule.outbound)
+                .and_then(|outbound| Rule::new(rule, outbound, &providers))
+                .map_err(|err| invalid(format!("routing rule {}: {}", i + 1, err)))?;
+            rules.push(rule);
+        }
+        let final_outbound = resolve_outbound(config, &config.routing.final_outbound)
+            .map_err(|err| invalid(format!("routing final: {}", err)))?;
+        let ech = match config.routing.ech.as_str() {
+            ECH_SNI => EchPolicy::Sni,
+            ECH_IP => EchPolicy::Ip,
+            name => EchPolicy::Outbound(
+                resolve_outbound(config, name)
+                    .map_err(|err| invalid(format!("routing ech: {}", err)))?,
+            ),
+        };
+        let mut ech_public_names = DomainTrie::default();
+        for name in config.routing.ech_public_names.iter() {
+            ech_public_names.insert_suffix(&normalize_domain(name.trim_start_matches('.')));
+        }
+        let uses_proxy = final_outbound == Outbound::Proxy
+            || ech == EchPolicy::Outbound(Outbound::Proxy)
+            || rules.iter().any(|rule| rule.outbound == Outbound::Proxy);
+        if uses_proxy && config.upstreams.is_empty() {
+            return Err(invalid(String::from(
+                "no upstream configured, use --socks5 or add one to the config file",
+            )));
+        }
+        Ok(Router {
+            rules,
+            final_outbound,
+            ech,
+            ech_public_names,
+        })
+    }
+
+    // 嗅探出的域名能不能用来替换 dest 和匹配规则
+    pub fn use_sniffed_domain(&self, sniffed: &Sniffed) -> bool {
+        !self.is_ech(sniffed) || self.ech == EchPolicy::Sni
+    }
+
+    // 线上分不出 GREASE ECH 和真的 ECH，Chrome 没有 ECHConfig 时每个连接都带 GREASE ECH
+    // 真的 ECH 的 outer SNI 是 ECHConfig 里的 public name，只认配置的这些
+    fn is_ech(&self, sniffed: &Sniffed) -> bool {
+        match (sniffed.ech, sniffed.domain.as_deref()) {
+            (Some(Ech::Outer { .. }), Some(domain)) => {
+                self.ech_public_names.contains(&normalize_domain(domain))
+            }
+            _ => false,
+        }
+    }
+
+    // 按顺序匹配，第一条命中的规则生效
+    pub fn route(&self, session: &Session<'_>) -> (Outbound, Matched) {
+        let sniffed = match session.sniffed {
+            Some(sniffed) if !self.use_sniffed_domain(sniffed) => sniffed,
+            _ => return self.route_rules(session),
+        };
+        if let EchPolicy::Outbound(outbound) = self.ech {
+            return (outbound, Matched::Ech);
+        }
+        // 去掉 outer SNI，其他嗅探结果照常匹配
+        let sniffed = Sniffed {
+            domain: None,
+            ..sniffed.clone()
+        };
+        self.route_rules(&Session {
+            sniffed: Some(&sniffed),
+            ..*session
+        })
+    }
+
+    fn route_rules(&self, session: &Session<'_>) -> (Outbound, Matched) {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.matches(session) {
+                return (rule.outbound, Matched::Rule(i));
+            }
+        }
+        (self.final_outbound, Matched::Final)
+    }
+}
+
+// 加载规则里用到的 geoip:xx 和 geosite:xx
+fn load_geo(config: &Config) -> io::Result<Vec<Provider>> {
+    let routing = &config.routing;
+    let mut providers = Vec::new();
+    let names = geo_names(config, geo::GEOIP_PREFIX);
+    if !names.is_empty() {
+        let path = geo_path(&routing.geoip, names[0], "geoip")?;
+        providers.extend(geo::load_geoip(path, &names)?);
+    }
+    let names = geo_names(config, geo::GEOSITE_PREFIX);
+    if !names.is_empty() {
+        let path = geo_path(&routing.geosite, names[0], "geosite")?;
+        providers.extend(geo::load_geosite(path, &names)?);
+    }
+    Ok(providers)
+}
+
+fn geo_names<'a>(config: &'a Config, prefix: &str) -> Vec<&'a str> {
+    let mut names: Vec<&str> = config
+        .routing
+        .rules
+        .iter()
+        .flat_map(|rule| rule.provider.iter())
+        .map(|name| name.as_str())
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+fn geo_path<'a>(path: &'a Option<PathBuf>, name: &str, field: &str) -> io::Result<&'a Path> {
+    path.as_deref().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is used but routing.{} is not configured", name, field),
+        )
+    })
+}
+
+// proxy、direct、reject 或者 upstream 的名字
+fn resolve_outbound(config: &Config, name: &str) -> Result<Outbound, String> {
+    let outbound = match name {
+        OUTBOUND_PROXY => Outbound::Proxy,
+        OUTBOUND_DIRECT => Outbound::Direct,
+        OUTBOUND_REJECT => Outbound::Reject,
+        name => match config.upstreams.iter().position(|u| u.name == name) {
+            Some(index) if !name.is_empty() => Outbound::Upstream(index),
+            _ => return Err(format!("unknown outbound {}", name)),
+        },
+    };
+    Ok(outbound)
+}
+
+impl fmt::Display for Matched {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // 和配置文件里一样从 1 开始
+            Matched::Rule(i) => write!(f, "rule {}", i + 1),
+            Matched::Final => write!(f, "final"),
+            Matched::Ech => write!(f, "ech"),
+        }
+    }
+}
+
+#[cfg(test)]
+fn route_to(
+    router: &Router,
+    dest: crate::client::Destination,
+    src: &str,
+    inbound: &str,
+) -> Outbound {
+    let session = Session {
+        dest: &dest,
+        src: src.parse().unwrap(),
+        inbound,
+        user: None,
+        sniffed: None,
+    };
+    router.route(&session).0
+}
+
+#[test]
+fn test_route() {
+    use std::str::FromStr;
+    let mut config = Config::from_str(
+        r#"
+listeners:
+  - port: 1080
+upstreams:
+  - name: us
+    addr: 127.0.0.1:1081
+routing:
+  rules:
+    - domain: [Exact.example.com]
+      outbound: reject
+    - domain_suffix: [example.com]
+      domain_keyword: [google]
+      domain_regex: ['^cdn\d+\.']
+      outbound: direct
+    - ip_cidr: [10.0.0.0/8, "fd00::/8"]
+      port: ["22", "8000-9000"]
+      outbound: direct
+    - src_cidr: [192.168.1.0/24]
+      inbound: [lan]
+      outbound: us
+  final: proxy
+"#,
+    )
+    .unwrap();
+    let router = Router::new(&config).unwrap();
+    let src = "127.0.0.1:5000";
+    let route = |dest, src, inbound| route_to(&router, dest, src, inbound);
+    assert_eq!(
+        route(("exact.example.com.", 443).into(), src, ""),
+        Outbound::Reject
+    );
+    assert_eq!(
+        route(("a.example.com", 443).into(), src, ""),
+        Outbound::Direct
+    );
+    assert_eq!(
+        route(("example.com", 443).into(), src, ""),
+        Outbound::Direct
+    );
+    assert_eq!(
+        route(("badexample.com", 443).into(), src, ""),
+        Outbound::Proxy
+    );
+    assert_eq!(
+        route(("www.google.co.jp", 443).into(), src, ""),
+        Outbound::Direct
+    );
+    assert_eq!(
+        route(("cdn42.foo.net", 80).into(), src, ""),
+        Outbound::Direct
+    );
+    // ip_cidr 和 port 需要同时命中
+    let ip = |s: &str| std::net::SocketAddr::from_str(s).unwrap().into();
+    assert_eq!(route(ip("10.1.2.3:8080"), src, ""), Outbound::Direct);
+    assert_eq!(route(ip("[fd00::1]:22"), src, ""), Outbound::Direct);
+    assert_eq!(route(ip("10.1.2.3:443"), src, ""), Outbound::Proxy);
+    assert_eq!(
+        route(ip("1.1.1.1:443"), "192.168.1.9:1", "lan"),
+        Outbound::Upstream(0)
+    );
+    assert_eq!(
+        route(ip("1.1.1.1:443"), "192.168.1.9:1", "wan"),
+        Outbound::Proxy
+    );
+
+    config.routing.rules[0].outbound = String::from("nowhere");
+    assert!(Router::new(&config).is_err());
+    config.routing.rules[0].outbound = String::from("direct");
+    config.routing.rules[0].ip_cidr = vec![String::from("10.0.0.0/40")];
+    assert!(Router::new(&config).is_err());
+}
+
+#[test]
+fn test_route_sniffed() {
+    use crate::sniff::{Protocol, Sniffed};
+    use std::str::FromStr;
+    let config = Config::from_str(
+        r#"
+listeners:
+  - port: 1080
+routing:
+  rules:
+    - protocol: [bittorrent]
+      outbound: reject
+    - alpn: [h2]
+      ja4: [T13D1516H2_8daaf6152771_e5627efa2ab1]
+      outbound: reject
+    - domain_suffix: [example.com]
+      outbound: direct
+  final: proxy
+upstreams:
+  - addr: 127.0.0.1:1081
+"#,
+    )
+    .unwrap();
+    let router = Router::new(&config).unwrap();
+    let dest = "93.184.216.34:443"
+        .parse::<std::net::SocketAddr>()
+        .unwrap()
+        .into();
+    let route = |sniffed: Option<&Sniffed>| {
+        let session = Session {
+            dest: &dest,
+            src: "127.0.0.1:5000".parse().unwrap(),
+            inbound: "",
+            user: None,
+            sniffed,
+        };
+        router.route(&session).0
+    };
+    let sniffed = |protocol, domain: Option<&str>| Sniffed::new(protocol, domain.map(Box::from));
+    assert_eq!(route(None), Outbound::Proxy);
+    // 域名没有替换 dest 时也用来匹配
+    let tls = sniffed(Protocol::Tls, Some("www.example.com"));
+    assert_eq!(route(Some(&tls)), Outbound::Direct);
+    let bt = sniffed(Protocol::Bittorrent, None);
+    assert_eq!(route(Some(&bt)), Outbound::Reject);
+    // alpn 和 ja4 需要同时命中，ja4 不区分大小写
+    let mut tls = sniffed(Protocol::Tls, Some("www.example.com"));
+    tls.alpn = vec![Box::from("http/1.1"), Box::from("h2")];
+    tls.ja4 = Some(Box::from("t13d1516h2_8daaf6152771_e5627efa2ab1"));
+    assert_eq!(route(Some(&tls)), Outbound::Reject);
+    // ALPN 区分大小写
+    tls.alpn = vec![Box::from("H2")];
+    assert_eq!(route(Some(&tls)), Outbound::Direct);
+    tls.alpn.pop();
+    assert_eq!(route(Some(&tls)), Outbound::Direct);
+}
+
+#[test]
+fn test_route_user() {
+    use std::str::FromStr;
+    let config = Config::from_str(
+        r#"
+listeners:
+  - port: 1080
+    users:
+      alice: secret
+upstreams:
+  - addr: 127.0.0.1:1081
+routing:
+  rules:
+    - user: [alice]
+      port: ["22"]
+      outbound: direct
+    - user: [bob]
+      outbound: reject
+"#,
+    )
+    .unwrap();
+    let router = Router::new(&config).unwrap();
+    let route = |dest: &crate::client::Destination, user| {
+        let session = Session {
+            dest,
+            src: "127.0.0.1:5000".parse().unwrap(),
+            inbound: "",
+            user,
+            sniffed: None,
+        };
+        router.route(&session).0
+    };
+    let ssh = ("example.com", 22).into();
+    assert_eq!(route(&ssh, Some("alice")), Outbound::Direct);
+    assert_eq!(route(&ssh, None), Outbound::Proxy);
+    assert_eq!(
+        route(&("example.com", 443).into(), Some("alice")),
+        Outbound::Proxy
+    );
+    assert_eq!(route(&ssh, Some("bob")), Outbound::Reject);
+}
+
+#[test]
+fn test_route_ech() {
+    use crate::{
+        sniff::{Protocol, Sniffed},
+        tls::Ech,
+    };
+    use std::str::FromStr;
+    let mut config = Config::from_str(
+        r#"
+listeners:
+  - port: 1080
+upstreams:
+  - name: us
+    addr: 127.0.0.1:1081
+routing:
+  rules:
+    - domain_suffix: [cloudflare-ech.com]
+      outbound: direct
+    - ip_cidr: [104.16.0.0/12]
+      protocol: [tls]
+      outbound: us
+  final: reject
+"#,
+    )
+    .unwrap();
+    let dest = "104.16.1.1:443"
+        .parse::<std::net::SocketAddr>()
+        .unwrap()
+        .into();
+    let mut sniffed = Sniffed::new(Protocol::Tls, Some(Box::from("cloudflare-ech.com")));
+    sniffed.ech = Some(Ech::Outer {
+        kdf_id: 1,
+        aead_id: 1,
+        config_id: 0,
+        enc_len: 32,
+        payload_len: 239,
+    });
+    let route = |config: &Config, sniffed: &Sniffed| {
+        let router = Router::new(config).unwrap();
+        let session = Session {
+            dest: &dest,
+            src: "127.0.0.1:5000".parse().unwrap(),
+            inbound: "",
+            user: None,
+            sniffed: Some(sniffed),
+        };
+        (router.use_sniffed_domain(sniffed), router.route(&session))
+    };
+    // 默认用 outer SNI
+    assert_eq!(
+        route(&config, &sniffed),
+        (true, (Outbound::Direct, Matched::Rule(0)))
+    );
+    // 不看域名，其他嗅探结果照常匹配
+    config.routing.ech = String::from("ip");
+    assert_eq!(
+        route(&config, &sniffed),
+        (false, (Outbound::Upstream(0), Matched::Rule(1)))
+    );
+    config.routing.ech = String::from("us");
+    assert_eq!(
+        route(&config, &sniffed),
+        (false, (Outbound::Upstream(0), Matched::Ech))
+    );
+    // 没有 ECH 的连接不受影响
+    let plain = Sniffed::new(Protocol::Tls, Some(Box::from("cloudflare-ech.com")));
+    assert_eq!(
+        route(&config, &plain),
+        (true, (Outbound::Direct, Matched::Rule(0)))
+    );
+    // outer SNI 不是 public name 的是 GREASE ECH，SNI 是真实域名，照常匹配，不走 routing.ech
+    let mut grease = Sniffed::new(Protocol::Tls, Some(Box::from("www.cloudflare.com")));
+    grease.ech = sniffed.ech;
+    assert_eq!(
+        route(&config, &grease),
+        (true, (Outbound::Upstream(0), Matched::Rule(1)))
+    );
+    grease.domain = Some(Box::from("Edge.Cloudflare-ECH.com"));
+    assert_eq!(
+        route(&config, &grease),
+        (false, (Outbound::Upstream(0), Matched::Ech))
+    );
+    config.routing.ech_public_names = vec![String::from("ech.example")];
+    assert_eq!(
+        route(&config, &sniffed),
+        (true, (Outbound::Direct, Matched::Rule(0)))
+    );
+    config.routing.ech = String::from("nowhere");
+    assert!(Router::new(&config).is_err());
+}
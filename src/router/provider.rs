@@ -0,0 +1,168 @@
+// 从文件加载的 ip 段和域名列表，规则里用 provider 引用
+// geoip.dat 和 geosite.dat 里的条目也转换成 provider，见 geo.rs
+use std::{fs, io, net::IpAddr};
+
+use log::info;
+use regex::RegexSet;
+
+use crate::config::{ProviderConfig, ProviderType};
+
+use super::{
+    rule::normalize_domain,
+    trie::{DomainTrie, IpTrie},
+    Cidr,
+};
+
+#[derive(Debug)]
+pub struct Provider {
+    pub name: String,
+    ips: IpTrie,
+    // geoip 的 reverse_match，不在 ips 里的算命中
+    reverse_ips: bool,
+    domains: DomainTrie,
+    // 下面两个只有 geosite 会用到
+    keywords: Vec<String>,
+    regex: Option<RegexSet>,
+}
+
+impl Provider {
+    pub(super) fn new(name: &str) -> Provider {
+        Provider {
+            name: String::from(name),
+            ips: IpTrie::default(),
+            reverse_ips: false,
+            domains: DomainTrie::default(),
+            keywords: Vec::new(),
+            regex: None,
+        }
+    }
+
+    pub fn load(name: &str, config: &ProviderConfig) -> io::Result<Provider> {
+        let path = &config.path;
+        let content = fs::read_to_string(path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("failed to read provider file {}: {}", path.display(), err),
+            )
+        })?;
+        let provider = Provider::parse(name, config.provider_type, &content).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })?;
+        info!(
+            "provider {} loaded {} entries from {}",
+            name,
+            provider.len(),
+            path.display()
+        );
+        Ok(provider)
+    }
+
+    // 每行一条，# 开头为注释
+    // ip_cidr: 1.2.3.0/24 或者单个地址
+    // domain: example.com 匹配它和它的子域名，full:example.com 只匹配它本身
+    pub fn parse(
+        name: &str,
+        provider_type: ProviderType,
+        content: &str,
+    ) -> Result<Provider, String> {
+        let mut provider = Provider::new(name);
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = match provider_type {
+                ProviderType::IpCidr => line.parse().map(|cidr| provider.insert_cidr(cidr)),
+                ProviderType::Domain => provider.insert_domain(line),
+            };
+            result.map_err(|err| format!("{} at line {}", err, i + 1))?;
+        }
+        Ok(provider)
+    }
+
+    fn insert_domain(&mut self, line: &str) -> Result<(), String> {
+        let (full, domain) = match line.split_once(':') {
+            Some(("full", domain)) => (true, domain),
+            Some(("domain", domain)) => (false, domain),
+            Some(_) => return Err(format!("unsupported domain entry {}", line)),
+            None => (false, line),
+        };
+        let domain = domain.trim_start_matches('.');
+        if normalize_domain(domain).is_empty() {
+            return Err(format!("invalid domain entry {}", line));
+        }
+        if full {
+            self.insert_full(domain);
+        } else {
+            self.insert_suffix(domain);
+        }
+        Ok(())
+    }
+
+    pub(super) fn insert_cidr(&mut self, cidr: Cidr) {
+        self.ips.insert(cidr);
+    }
+    pub(super) fn set_reverse_ips(&mut self, reverse: bool) {
+        self.reverse_ips = reverse;
+    }
+    pub(super) fn insert_full(&mut self, domain: &str) {
+        self.domains.insert_full(&normalize_domain(domain));
+    }
+    pub(super) fn insert_suffix(&mut self, domain: &str) {
+        self.domains.insert_suffix(&normalize_domain(domain));
+    }
+    pub(super) fn insert_keyword(&mut self, keyword: &str) {
+        self.keywords.push(keyword.to_ascii_lowercase());
+    }
+    pub(super) fn set_regex(&mut self, patterns: &[String]) -> Result<(), String> {
+        if !patterns.is_empty() {
+            self.regex = Some(RegexSet::new(patterns).map_err(|err| err.to_string())?);
+        }
+        Ok(())
+    }
+
+    // domain 已经是 normalize_domain 处理过的
+    pub fn contains_domain(&self, domain: &str) -> bool {
+        self.domains.contains(domain)
+            || self.keywords.iter().any(|k| domain.contains(k.as_str()))
+            || self.regex.as_ref().is_some_and(|set| set.is_match(domain))
+    }
+    pub fn contains_ip(&self, ip: IpAddr) -> bool {
+        self.ips.contains(ip) != self.reverse_ips
+    }
+    pub fn len(&self) -> usize {
+        self.ips.len()
+            + self.domains.len()
+            + self.keywords.len()
+            + self.regex.as_ref().map_or(0, |set| set.len())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test]
+fn test_parse_provider() {
+    let provider = Provider::parse(
+        "ads",
+        ProviderType::Domain,
+        "# ads\n\nDoubleClick.net\nfull:ads.example.com\ndomain:.tracker.io.\n",
+    )
+    .unwrap();
+    assert_eq!(provider.len(), 3);
+    assert!(provider.contains_domain("stats.doubleclick.net"));
+    assert!(provider.contains_domain("ads.example.com"));
+    assert!(!provider.contains_domain("a.ads.example.com"));
+    assert!(provider.contains_domain("tracker.io"));
+    let err = Provider::parse("ads", ProviderType::Domain, "a.com\nkeyword:ads\n").unwrap_err();
+    assert!(err.contains("line 2"), "{}", err);
+
+    let provider = Provider::parse("cn", ProviderType::IpCidr, "1.0.1.0/24\n240e::/20\n").unwrap();
+    assert!(provider.contains_ip("1.0.1.9".parse().unwrap()));
+    assert!(provider.contains_ip("240e:3b0::1".parse().unwrap()));
+    assert!(!provider.contains_ip("1.0.2.1".parse().unwrap()));
+    assert!(Provider::parse("cn", ProviderType::IpCidr, "example.com\n").is_err());
+}
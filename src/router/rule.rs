@@ -0,0 +1,369 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use regex::RegexSet;
+
+use crate::{
+    client::{Address, Destination},
+    config::RuleConfig,
+    sniff::{self, Sniffed},
+};
+
+use super::{
+    provider::Provider,
+    trie::{DomainTrie, IpTrie},
+    Outbound,
+};
+
+// 一条编译好的规则
+// 目标地址的条件（domain*、ip_cidr、provider）之间任意一项命中即可
+// 其他条件（port、src_cidr、inbound、user、protocol、alpn、ja3、ja4）配置了就需要同时命中
+#[derive(Debug)]
+pub struct Rule {
+    // domain 和 domain_suffix
+    domains: DomainTrie,
+    domain_keyword: Vec<String>,
+    domain_regex: Option<RegexSet>,
+    ip_cidr: IpTrie,
+    providers: Vec<Arc<Provider>>,
+    port: Vec<PortRange>,
+    src_cidr: Vec<Cidr>,
+    inbound: Vec<String>,
+    user: Vec<String>,
+    protocol: Vec<sniff::Protocol>,
+    // ALPN 是区分大小写的字节串，h2 和 H2 是不同的协议，原样比较
+    // https://tools.ietf.org/html/rfc7301#section-6
+    alpn: Vec<String>,
+    // 小写的指纹
+    ja3: Vec<String>,
+    ja4: Vec<String>,
+    pub outbound: Outbound,
+}
+
+// 路由时需要的连接信息
+#[derive(Clone, Copy)]
+pub struct Session<'a> {
+    pub dest: &'a Destination,
+    pub src: SocketAddr,
+    // listener 的名字
+    pub inbound: &'a str,
+    // socks5、http 代理认证过的用户名
+    pub user: Option<&'a str>,
+    // 透明代理嗅探的结果，域名没有替换 dest 时也用来匹配
+    pub sniffed: Option<&'a Sniffed>,
+}
+
+impl Rule {
+    // providers 是已经加载好的 routing.providers
+    pub fn new(
+        config: &RuleConfig,
+        outbound: Outbound,
+        providers: &HashMap<String, Arc<Provider>>,
+    ) -> Result<Rule, String> {
+        let domain_regex = if config.domain_regex.is_empty() {
+            None
+        } else {
+            Some(RegexSet::new(&config.domain_regex).map_err(|err| err.to_string())?)
+        };
+        let mut domains = DomainTrie::default();
+        for domain in config.domain.iter() {
+            domains.insert_full(&normalize_domain(domain));
+        }
+        for domain in config.domain_suffix.iter() {
+            domains.insert_suffix(&normalize_domain(domain.trim_start_matches('.')));
+        }
+        let mut ip_cidr = IpTrie::default();
+        for cidr in parse_all(&config.ip_cidr)? {
+            ip_cidr.insert(cidr);
+        }
+        let providers = config
+            .provider
+            .iter()
+            .map(|name| {
+                providers
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| format!("unknown provider {}", name))
+            })
+            .collect::<Result<_, _>>()?;
+        let rule = Rule {
+            domains,
+            domain_keyword: lowercase_all(&config.domain_keyword),
+            domain_regex,
+            ip_cidr,
+            providers,
+            port: parse_all(&config.port)?,
+            src_cidr: parse_all(&config.src_cidr)?,
+            inbound: config.inbound.clone(),
+            user: config.user.clone(),
+            protocol: config.protocol.clone(),
+            alpn: config.alpn.clone(),
+            ja3: lowercase_all(&config.ja3),
+            ja4: lowercase_all(&config.ja4),
+            outbound,
+        };
+        if !rule.has_dest_condition()
+            && rule.port.is_empty()
+            && rule.src_cidr.is_empty()
+            && rule.inbound.is_empty()
+            && rule.user.is_empty()
+            && rule.protocol.is_empty()
+            && !rule.has_tls_condition()
+        {
+            return Err(String::from("rule has no condition"));
+        }
+        Ok(rule)
+    }
+
+    fn has_dest_condition(&self) -> bool {
+        !self.domains.is_empty()
+            || !self.domain_keyword.is_empty()
+            || self.domain_regex.is_some()
+            || !self.ip_cidr.is_empty()
+            || !self.providers.is_empty()
+    }
+
+    fn has_tls_condition(&self) -> bool {
+        !self.alpn.is_empty() || !self.ja3.is_empty() || !self.ja4.is_empty()
+    }
+
+    pub fn matches(&self, session: &Session<'_>) -> bool {
+        if self.has_dest_condition() && !self.match_dest(session) {
+            return false;
+        }
+        if !self.port.is_empty() && !self.port.iter().any(|p| p.contains(session.dest.port)) {
+            return false;
+        }
+        if !self.src_cidr.is_empty() && !self.src_cidr.iter().any(|c| c.contains(session.src.ip()))
+        {
+            return false;
+        }
+        if !self.inbound.is_empty() && !self.inbound.iter().any(|i| i == session.inbound) {
+            return false;
+        }
+        if !self.user.is_empty()
+            && !session
+                .user
+                .is_some_and(|u| self.user.iter().any(|v| v == u))
+        {
+            return false;
+        }
+        if !self.protocol.is_empty() {
+            match session.sniffed {
+                Some(sniffed) if self.protocol.contains(&sniffed.protocol) => (),
+                _ => return false,
+            }
+        }
+        if self.has_tls_condition() && !session.sniffed.is_some_and(|s| self.match_tls(s)) {
+            return false;
+        }
+        true
+    }
+
+    // 没有嗅探出 tls 时都不命中
+    fn match_tls(&self, sniffed: &Sniffed) -> bool {
+        let matches = |list: &[String], value: Option<&str>| {
+            list.is_empty() || value.is_some_and(|v| list.iter().any(|item| item == v))
+        };
+        (self.alpn.is_empty()
+            || sniffed
+                .alpn
+                .iter()
+                .any(|a| self.alpn.iter().any(|b| **a == **b)))
+            && matches(&self.ja3, sniffed.ja3.as_deref())
+            && matches(&self.ja4, sniffed.ja4.as_deref())
+    }
+
+    fn match_dest(&self, session: &Session<'_>) -> bool {
+        let sniffed_domain = session
+            .sniffed
+            .and_then(|sniffed| sniffed.domain.as_deref());
+        self.match_host(&session.dest.host)
+            || sniffed_domain.is_some_and(|domain| self.match_domain(domain))
+    }
+
+    // 域名规则只匹配域名，不在这里做 DNS 解析
+    fn match_host(&self, host: &Address) -> bool {
+        match host {
+            Address::Ip(ip) => {
+                self.ip_cidr.contains(*ip) || self.providers.iter().any(|p| p.contains_ip(*ip))
+            }
+            Address::Domain(domain) => self.match_domain(domain),
+        }
+    }
+
+    fn match_domain(&self, domain: &str) -> bool {
+        let domain = normalize_domain(domain);
+        self.domains.contains(&domain)
+            || self.providers.iter().any(|p| p.contains_domain(&domain))
+            || self
+                .domain_keyword
+                .iter()
+                .any(|k| domain.contains(k.as_str()))
+            || self
+                .domain_regex
+                .as_ref()
+                .is_some_and(|set| set.is_match(&domain))
+    }
+}
+
+// 小写，去掉末尾的 .
+pub fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn lowercase_all(items: &[String]) -> Vec<String> {
+    items.iter().map(|item| item.to_ascii_lowercase()).collect()
+}
+
+fn parse_all<T>(items: &[String]) -> Result<Vec<T>, String>
+where
+    T: FromStr<Err = String>,
+{
+    items.iter().map(|item| item.parse()).collect()
+}
+
+// 1.2.3.0/24、2001:db8::/32，没有前缀长度时是单个地址
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Cidr, String> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > max {
+            return Err(format!("invalid prefix length {} for {}", prefix, addr));
+        }
+        Ok(Cidr { addr, prefix })
+    }
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // v4-mapped 的 v6 地址按 v4 处理
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            _ => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+// 比较前 prefix 位
+fn prefix_eq(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let full = (prefix / 8) as usize;
+    if a[..full] != b[..full] {
+        return false;
+    }
+    let rest = prefix % 8;
+    if rest == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest);
+    a[full] & mask == b[full] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let invalid = || format!("invalid cidr {}", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix = match (prefix, addr) {
+            (Some(prefix), _) => prefix.parse().map_err(|_| invalid())?,
+            (None, IpAddr::V4(_)) => 32,
+            (None, IpAddr::V6(_)) => 128,
+        };
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// "443" 或者 "8000-9000"，两端都包含
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<PortRange, String> {
+        let invalid = || format!("invalid port {}", s);
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (start.trim(), end.trim()),
+            None => (s.trim(), s.trim()),
+        };
+        let start: u16 = start.parse().map_err(|_| invalid())?;
+        let end: u16 = end.parse().map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+#[test]
+fn test_cidr() {
+    let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+    assert!(cidr.contains("10.1.200.3".parse().unwrap()));
+    assert!(cidr.contains("::ffff:10.1.0.1".parse().unwrap()));
+    assert!(!cidr.contains("10.2.0.1".parse().unwrap()));
+    let cidr: Cidr = "2001:db8::/33".parse().unwrap();
+    assert!(cidr.contains("2001:db8:7fff::1".parse().unwrap()));
+    assert!(!cidr.contains("2001:db8:8000::1".parse().unwrap()));
+    assert!(!cidr.contains("10.1.0.1".parse().unwrap()));
+    let host: Cidr = "192.168.1.1".parse().unwrap();
+    assert_eq!(host.to_string(), "192.168.1.1/32");
+    assert!("0.0.0.0/0"
+        .parse::<Cidr>()
+        .unwrap()
+        .contains("8.8.8.8".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("example.com/8".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn test_port_range() {
+    let range: PortRange = "8000-9000".parse().unwrap();
+    assert!(range.contains(8000) && range.contains(9000));
+    assert!(!range.contains(9001));
+    assert!("443".parse::<PortRange>().unwrap().contains(443));
+    assert!("9000-8000".parse::<PortRange>().is_err());
+    assert!("65536".parse::<PortRange>().is_err());
+}
@@ -0,0 +1,228 @@
+// 路由用的前缀树，规则和 provider 的条目可能有几万条，逐个比较太慢
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use super::Cidr;
+
+// 节点放在 Vec 里，用下标代替指针，每个节点只有两个 u32
+// 0 是根节点，不会作为子节点出现，所以用 0 表示没有子节点
+const NONE: u32 = 0;
+// 整个前缀都在集合里，更长的前缀不用再存
+const FULL: u32 = u32::MAX;
+
+#[derive(Debug, Default)]
+struct BitTrie {
+    nodes: Vec<[u32; 2]>,
+}
+
+impl BitTrie {
+    fn insert(&mut self, bits: &[u8], prefix: u8) {
+        if self.nodes.is_empty() {
+            self.nodes.push([NONE, NONE]);
+        }
+        let mut node = 0;
+        for i in 0..prefix as usize {
+            if self.nodes[node] == [FULL, FULL] {
+                // 已经被更短的前缀覆盖
+                return;
+            }
+            let bit = bit_at(bits, i);
+            node = match self.nodes[node][bit] {
+                NONE => {
+                    let child = self.nodes.len() as u32;
+                    self.nodes.push([NONE, NONE]);
+                    self.nodes[node][bit] = child;
+                    child as usize
+                }
+                child => child as usize,
+            };
+        }
+        // 下面的节点不再可达，留在 Vec 里，插入顺序一般是排好的，浪费不多
+        self.nodes[node] = [FULL, FULL];
+    }
+
+    fn contains(&self, bits: &[u8]) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let mut node = 0;
+        for i in 0..bits.len() * 8 {
+            match self.nodes[node][bit_at(bits, i)] {
+                NONE => return false,
+                FULL => return true,
+                child => node = child as usize,
+            }
+        }
+        self.nodes[node] == [FULL, FULL]
+    }
+}
+
+fn bit_at(bits: &[u8], i: usize) -> usize {
+    ((bits[i / 8] >> (7 - i % 8)) & 1) as usize
+}
+
+// 按位的前缀树，v4 和 v6 分开存
+#[derive(Debug, Default)]
+pub struct IpTrie {
+    v4: BitTrie,
+    v6: BitTrie,
+    len: usize,
+}
+
+impl IpTrie {
+    pub fn insert(&mut self, cidr: Cidr) {
+        match cidr.addr() {
+            IpAddr::V4(ip) => self.v4.insert(&ip.octets(), cidr.prefix()),
+            IpAddr::V6(ip) => self.v6.insert(&ip.octets(), cidr.prefix()),
+        }
+        self.len += 1;
+    }
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self.contains_v4(ip),
+            // v4-mapped 的 v6 地址按 v4 处理
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(v4) => self.contains_v4(v4),
+                None => self.contains_v6(ip),
+            },
+        }
+    }
+    fn contains_v4(&self, ip: Ipv4Addr) -> bool {
+        self.v4.contains(&ip.octets())
+    }
+    fn contains_v6(&self, ip: Ipv6Addr) -> bool {
+        self.v6.contains(&ip.octets())
+    }
+    // 插入过的条目数，包括重复的
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+// 按 label 从右往左存的后缀树
+// 边按 label 分组，label -> [(父节点, 子节点)]，按父节点排序，相同的 label 只存一份
+// 节点本身只有两个标记
+#[derive(Debug, Default)]
+pub struct DomainTrie {
+    edges: HashMap<Box<str>, Vec<(u32, u32)>>,
+    nodes: Vec<DomainNode>,
+    len: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct DomainNode {
+    // 这个域名本身
+    full: bool,
+    // 这个域名和它的子域名
+    suffix: bool,
+}
+
+impl DomainTrie {
+    // domain 需要是小写、没有首尾 . 的
+    pub fn insert_full(&mut self, domain: &str) {
+        let node = self.insert(domain);
+        self.nodes[node].full = true;
+    }
+    pub fn insert_suffix(&mut self, domain: &str) {
+        let node = self.insert(domain);
+        self.nodes[node].suffix = true;
+    }
+    fn insert(&mut self, domain: &str) -> usize {
+        if self.nodes.is_empty() {
+            self.nodes.push(DomainNode::default());
+        }
+        self.len += 1;
+        let mut node = 0;
+        for label in domain.rsplit('.') {
+            if let Some(child) = self.get(node, label) {
+                node = child;
+                continue;
+            }
+            let child = self.nodes.len() as u32;
+            self.nodes.push(DomainNode::default());
+            let edges = match self.edges.get_mut(label) {
+                Some(edges) => edges,
+                None => self.edges.entry(Box::from(label)).or_default(),
+            };
+            let pos = edges.partition_point(|&(parent, _)| parent < node as u32);
+            edges.insert(pos, (node as u32, child));
+            node = child as usize;
+        }
+        node
+    }
+    pub fn contains(&self, domain: &str) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let mut node = 0;
+        for label in domain.rsplit('.') {
+            node = match self.get(node, label) {
+                Some(child) => child,
+                None => return false,
+            };
+            if self.nodes[node].suffix {
+                return true;
+            }
+        }
+        self.nodes[node].full
+    }
+    fn get(&self, node: usize, label: &str) -> Option<usize> {
+        let edges = self.edges.get(label)?;
+        let pos = edges
+            .binary_search_by_key(&(node as u32), |&(parent, _)| parent)
+            .ok()?;
+        Some(edges[pos].1 as usize)
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[test]
+fn test_ip_trie() {
+    let mut trie = IpTrie::default();
+    assert!(!trie.contains("10.0.0.1".parse().unwrap()));
+    for cidr in &["10.1.0.0/16", "10.1.2.0/24", "192.168.1.1", "2001:db8::/33"] {
+        trie.insert(cidr.parse().unwrap());
+    }
+    assert!(trie.contains("10.1.200.3".parse().unwrap()));
+    assert!(trie.contains("10.1.2.3".parse().unwrap()));
+    assert!(trie.contains("::ffff:10.1.0.1".parse().unwrap()));
+    assert!(!trie.contains("10.2.0.1".parse().unwrap()));
+    assert!(trie.contains("192.168.1.1".parse().unwrap()));
+    assert!(!trie.contains("192.168.1.2".parse().unwrap()));
+    assert!(trie.contains("2001:db8:7fff::1".parse().unwrap()));
+    assert!(!trie.contains("2001:db8:8000::1".parse().unwrap()));
+    // 更短的前缀覆盖已有的
+    trie.insert("10.0.0.0/8".parse().unwrap());
+    assert!(trie.contains("10.2.0.1".parse().unwrap()));
+    trie.insert("::/0".parse().unwrap());
+    assert!(trie.contains("fe80::1".parse().unwrap()));
+    assert!(!trie.contains("11.0.0.1".parse().unwrap()));
+}
+
+#[test]
+fn test_domain_trie() {
+    let mut trie = DomainTrie::default();
+    assert!(!trie.contains("example.com"));
+    trie.insert_suffix("example.com");
+    trie.insert_full("www.example.org");
+    trie.insert_suffix("cn");
+    assert!(trie.contains("example.com"));
+    assert!(trie.contains("a.b.example.com"));
+    assert!(!trie.contains("badexample.com"));
+    assert!(!trie.contains("com"));
+    assert!(trie.contains("www.example.org"));
+    assert!(!trie.contains("a.www.example.org"));
+    assert!(!trie.contains("example.org"));
+    assert!(trie.contains("baidu.cn"));
+    assert_eq!(trie.len(), 3);
+}
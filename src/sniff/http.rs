@@ -0,0 +1,140 @@
+// 从明文 http 请求里取出域名，用法和 tls::parse_client_hello 一样
+// 只看第一个请求的请求行和 Host，数据不完整时尽量解析已经读到的部分
+// https://tools.ietf.org/html/rfc7230#section-3.1.1
+// https://tools.ietf.org/html/rfc7230#section-5.4
+use std::str::from_utf8;
+
+use log::debug;
+
+// 请求行和 header 一般都在第一个包里，超过的部分不再找
+pub const MAX_HEAD_LEN: usize = 8192;
+
+pub struct HttpRequestHead {
+    pub method: Box<str>,
+    // 只有域名时才有，ip 字面量没有意义
+    pub host: Option<Box<str>>,
+    // 读到了 header 结尾的空行或者完整的 Host 行，再多的数据也不会改变 host
+    pub complete: bool,
+}
+
+pub fn parse_request_head(data: &[u8]) -> Result<HttpRequestHead, &'static str> {
+    let data = &data[..data.len().min(MAX_HEAD_LEN)];
+    let mut lines = data.split(|&b| b == b'\n').peekable();
+    // method SP request-target SP HTTP-version CRLF
+    let request_line = lines.next().ok_or("no request line")?;
+    let request_line = from_utf8(trim_cr(request_line)).map_err(|_| "invalid request line")?;
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if parts.next().is_none() => {
+            (method, target, version)
+        }
+        _ => return Err("invalid request line"),
+    };
+    if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+        return Err("invalid method");
+    }
+    if !version.starts_with("HTTP/1.") {
+        return Err("not a HTTP/1.x request");
+    }
+    // absolute-form 时以请求行里的为准，忽略 Host
+    let mut host = target
+        .strip_prefix("http://")
+        .map(|rest| rest.split(['/', '?']).next().unwrap_or_default());
+    let mut complete = host.is_some();
+    if host.is_none() {
+        while let Some(line) = lines.next() {
+            // 最后一段后面没有 \n，还没收完
+            if lines.peek().is_none() {
+                break;
+            }
+            let line = trim_cr(line);
+            if line.is_empty() {
+                complete = true;
+                break;
+            }
+            let line = match from_utf8(line) {
+                Ok(line) => line,
+                Err(_) => continue,
+            };
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("host") {
+                    host = Some(value.trim());
+                    complete = true;
+                    break;
+                }
+            }
+        }
+    }
+    let host = host.and_then(domain_of).map(Box::from);
+    debug!("HTTP parser domain: {:?}", host);
+    Ok(HttpRequestHead {
+        method: Box::from(method),
+        host,
+        complete,
+    })
+}
+
+// 请求行还没收完，目前收到的可能是 http 请求的开头
+pub fn is_partial(data: &[u8]) -> bool {
+    if data.contains(&b'\n') || data.len() >= MAX_HEAD_LEN {
+        return false;
+    }
+    let method_len = data.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    match data.get(method_len) {
+        None => true,
+        Some(b' ') => method_len > 0,
+        Some(_) => false,
+    }
+}
+
+fn trim_cr(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// host [":" port]，去掉端口，ip 和不合法的域名返回 None
+fn domain_of(host: &str) -> Option<&str> {
+    if host.starts_with('[') {
+        // ipv6 字面量
+        return None;
+    }
+    let domain = match host.rsplit_once(':') {
+        Some((domain, port)) if port.bytes().all(|b| b.is_ascii_digit()) => domain,
+        Some(_) => return None,
+        None => host,
+    };
+    if !super::is_domain(domain) {
+        return None;
+    }
+    Some(domain)
+}
+
+#[test]
+fn test_parse_request_head() {
+    let head = parse_request_head(
+        b"GET /index.html HTTP/1.1\r\nUser-Agent: curl\r\nhost: Example.com:8080\r\n\r\n",
+    )
+    .unwrap();
+    assert_eq!(&*head.method, "GET");
+    assert_eq!(head.host.as_deref(), Some("Example.com"));
+    // absolute-form
+    let head =
+        parse_request_head(b"GET http://a.example.com/x HTTP/1.1\r\nHost: b.example.com\r\n\r\n")
+            .unwrap();
+    assert_eq!(head.host.as_deref(), Some("a.example.com"));
+    // 只收到一部分 header
+    let head = parse_request_head(b"POST / HTTP/1.0\nHost: example.org\nContent-Le").unwrap();
+    assert_eq!(head.host.as_deref(), Some("example.org"));
+    assert!(head.complete);
+    let head = parse_request_head(b"GET / HTTP/1.1\r\nHost: exam").unwrap();
+    assert_eq!((head.host, head.complete), (None, false));
+    let head = parse_request_head(b"GET / HTTP/1.1\r\nHost: 93.184.216.34\r\n\r\n").unwrap();
+    assert_eq!(head.host, None);
+    let head = parse_request_head(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n").unwrap();
+    assert_eq!(head.host, None);
+    // 超过 255 字节的域名没法写进 socks5 地址
+    let req = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", "a".repeat(256));
+    assert_eq!(parse_request_head(req.as_bytes()).unwrap().host, None);
+    assert!(parse_request_head(b"SSH-2.0-OpenSSH_8.9\r\n").is_err());
+    assert!(parse_request_head(b"PRI * HTTP/2.0\r\n\r\n").is_err());
+    assert!(parse_request_head(&[0x16, 0x03, 0x01, 0x00]).is_err());
+}
@@ -0,0 +1,344 @@
+// 透明代理时按 client 首包的内容判断协议，能取出域名的协议顺便取出域名
+// tls 的解析在 crate::tls
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::{config::SniffConfig, tls};
+
+pub mod http;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tls,
+    Http,
+    Ssh,
+    Bittorrent,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Protocol::Tls => "tls",
+            Protocol::Http => "http",
+            Protocol::Ssh => "ssh",
+            Protocol::Bittorrent => "bittorrent",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sniffed {
+    pub protocol: Protocol,
+    // tls 的 SNI 或者 http 的 Host
+    pub domain: Option<Box<str>>,
+    // 下面只有 tls 才有
+    pub alpn: Vec<Box<str>>,
+    pub ja3: Option<Box<str>>,
+    pub ja4: Option<Box<str>>,
+    // 带 ECH 时 domain 是 outer 的 public name，见 routing.ech
+    pub ech: Option<tls::Ech>,
+}
+
+impl Sniffed {
+    pub fn new(protocol: Protocol, domain: Option<Box<str>>) -> Sniffed {
+        Sniffed {
+            protocol,
+            domain,
+            alpn: Vec::new(),
+            ja3: None,
+            ja4: None,
+            ech: None,
+        }
+    }
+
+    fn from_client_hello(hello: tls::TlsClientHello) -> Sniffed {
+        Sniffed {
+            protocol: Protocol::Tls,
+            ja3: Some(hello.ja3().into_boxed_str()),
+            ja4: Some(hello.ja4().into_boxed_str()),
+            // SNI 会替换目标地址，不合法的当作没有
+            domain: hello.server_name.filter(|name| is_domain(name)),
+            alpn: hello.alpn,
+            ech: hello.ech,
+        }
+    }
+}
+
+// 嗅探出的名字能不能当作域名替换目标地址：LDH 和 _，不超过 255 字节，不是 ip
+pub fn is_domain(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 255
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+        && name.parse::<std::net::Ipv4Addr>().is_err()
+}
+
+// 最多读这么多数据来判断，超过还判断不出来就放弃
+// 最长的 client hello 加上每个 record 5 字节的头，record 最长 2^14
+pub const MAX_SNIFF_LEN: usize =
+    tls::MAX_HANDSHAKE_LEN + 5 * tls::MAX_HANDSHAKE_LEN.div_ceil(tls::MAX_RECORD_LEN);
+
+#[derive(Debug, PartialEq)]
+pub enum SniffResult<'a> {
+    // 认出来的协议和对应的配置
+    Found(Sniffed, &'a SniffConfig),
+    // 可能是某个协议，需要更多数据
+    Incomplete,
+    Unknown,
+}
+
+enum Probe {
+    Match(Sniffed),
+    Incomplete,
+    NoMatch,
+}
+
+// 按配置的顺序尝试，返回第一个认出来的协议
+// 数据到了 MAX_SNIFF_LEN 或者等不到更多数据时，Incomplete 当作 Unknown
+pub fn sniff<'a>(data: &[u8], sniffers: &'a [SniffConfig]) -> SniffResult<'a> {
+    let mut incomplete = false;
+    for sniffer in sniffers {
+        let probe = match sniffer.protocol {
+            Protocol::Tls => probe_tls(data),
+            Protocol::Http => probe_http(data),
+            Protocol::Ssh => probe_prefix(data, Protocol::Ssh, &[b"SSH-2.0-", b"SSH-1.99-"]),
+            Protocol::Bittorrent => {
+                probe_prefix(data, Protocol::Bittorrent, &[BITTORRENT_HANDSHAKE])
+            }
+        };
+        match probe {
+            Probe::Match(sniffed) => return SniffResult::Found(sniffed, sniffer),
+            Probe::Incomplete => incomplete = true,
+            Probe::NoMatch => (),
+        }
+    }
+    if incomplete {
+        SniffResult::Incomplete
+    } else {
+        SniffResult::Unknown
+    }
+}
+
+// 嗅探结束后每个连接记一次 client hello 的解析结果，见 tls::STATS
+// 没有配置 tls 时不记
+pub fn record_tls_stats(result: &SniffResult<'_>, data: &[u8], sniffers: &[SniffConfig]) {
+    if !sniffers.iter().any(|s| s.protocol == Protocol::Tls) {
+        return;
+    }
+    match result {
+        SniffResult::Found(sniffed, _) if sniffed.protocol == Protocol::Tls => {
+            tls::STATS.record_hello(sniffed.domain.is_some())
+        }
+        // 认出了其他协议，不再当作 tls 解析
+        SniffResult::Found(..) => tls::STATS.record_other_protocol(),
+        // client 什么都没发，不算 client hello 解析失败
+        _ if data.is_empty() => tls::STATS.record_no_data(),
+        // 没认出 tls 时再解析一次，取出失败的原因
+        _ => {
+            if let Err(err) = tls::parse_client_hello(data) {
+                tls::STATS.record_error(err);
+            }
+        }
+    }
+}
+
+// client hello 可能分在多个 record 和多个 tcp 包里
+fn probe_tls(data: &[u8]) -> Probe {
+    match tls::read_handshake(data) {
+        Ok(Some(message)) => match tls::parse_handshake(&message) {
+            Ok(hello) => Probe::Match(Sniffed::from_client_hello(hello)),
+            Err(_) => Probe::NoMatch,
+        },
+        Ok(None) => Probe::Incomplete,
+        Err(_) => Probe::NoMatch,
+    }
+}
+
+fn probe_http(data: &[u8]) -> Probe {
+    if http::is_partial(data) {
+        return Probe::Incomplete;
+    }
+    match http::parse_request_head(data) {
+        // 等到 header 结束或者 Host 出现，最多 MAX_HEAD_LEN
+        Ok(head) if !head.complete && data.len() < http::MAX_HEAD_LEN => Probe::Incomplete,
+        Ok(head) => Probe::Match(Sniffed::new(Protocol::Http, head.host)),
+        Err(_) => Probe::NoMatch,
+    }
+}
+
+// SSH 双方连上后都会先发 SSH-protoversion-softwareversion
+// https://tools.ietf.org/html/rfc4253#section-4.2
+// BitTorrent 握手以 19 "BitTorrent protocol" 开头
+// http://bittorrent.org/beps/bep_0003.html
+const BITTORRENT_HANDSHAKE: &[u8] = b"\x13BitTorrent protocol";
+
+fn probe_prefix(data: &[u8], protocol: Protocol, prefixes: &[&[u8]]) -> Probe {
+    if prefixes.iter().any(|prefix| data.starts_with(prefix)) {
+        Probe::Match(Sniffed::new(protocol, None))
+    } else if prefixes.iter().any(|prefix| prefix.starts_with(data)) {
+        Probe::Incomplete
+    } else {
+        Probe::NoMatch
+    }
+}
+
+#[test]
+fn test_sniff() {
+    let sniffers = |protocols: &[Protocol]| -> Vec<SniffConfig> {
+        protocols
+            .iter()
+            .map(|&protocol| SniffConfig {
+                protocol,
+                override_dest: true,
+            })
+            .collect()
+    };
+    let all = sniffers(&[
+        Protocol::Tls,
+        Protocol::Http,
+        Protocol::Ssh,
+        Protocol::Bittorrent,
+    ]);
+    let sniffed = |data: &[u8], sniffers: &[SniffConfig]| match sniff(data, sniffers) {
+        SniffResult::Found(sniffed, _) => Some(sniffed),
+        _ => None,
+    };
+
+    let http = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    assert_eq!(
+        sniffed(http, &all),
+        Some(Sniffed::new(Protocol::Http, Some(Box::from("example.com"))))
+    );
+    let hello = sniffed(tls::CLIENT_HELLO_WITH_SERVER_NAME, &all).unwrap();
+    assert_eq!(hello.domain.as_deref(), Some("www.google.com"));
+    assert_eq!(hello.alpn.first().map(|a| &**a), Some("h2"));
+    assert!(hello.ja3.is_some() && hello.ja4.is_some());
+    // 不合法的 SNI 不能用来替换目标地址，协议照样认出来
+    for name in [
+        &b"www.goo le.com"[..],
+        b"www\0google.com",
+        b"10.100.200.123",
+    ] {
+        let data = tls::CLIENT_HELLO_WITH_SERVER_NAME;
+        let at = data
+            .windows(14)
+            .position(|w| w == b"www.google.com")
+            .unwrap();
+        let mut data = data.to_vec();
+        data[at..at + 14].copy_from_slice(name);
+        let hello = sniffed(&data, &all).unwrap();
+        assert_eq!((hello.protocol, hello.domain), (Protocol::Tls, None));
+    }
+    assert!(is_domain("_dmarc.example.com"));
+    assert!(!is_domain(""));
+    assert!(!is_domain("93.184.216.34"));
+    assert!(!is_domain(&"a".repeat(256)));
+    let ssh = sniffed(b"SSH-2.0-OpenSSH_8.9p1\r\n", &all).unwrap();
+    assert_eq!((ssh.protocol, ssh.domain), (Protocol::Ssh, None));
+    let mut handshake = b"\x13BitTorrent protocol".to_vec();
+    handshake.extend_from_slice(&[0; 48]);
+    assert_eq!(
+        sniffed(&handshake, &all).unwrap().protocol,
+        Protocol::Bittorrent
+    );
+    // 没有配置的协议不会认出来
+    assert_eq!(sniffed(http, &sniffers(&[Protocol::Tls])), None);
+    assert_eq!(sniff(b"\x00\x01\x02\x03", &all), SniffResult::Unknown);
+    // 还不够判断
+    assert_eq!(sniff(b"", &all), SniffResult::Incomplete);
+    assert_eq!(sniff(b"SSH-2", &all), SniffResult::Incomplete);
+    assert_eq!(sniff(b"GET / HT", &all), SniffResult::Incomplete);
+    // 请求行完整但 header 还没到，不能当成没有 Host
+    assert_eq!(sniff(b"GET / HTTP/1.1\r\n", &all), SniffResult::Incomplete);
+    assert_eq!(
+        sniff(b"GET / HTTP/1.1\r\nAccept: */*\r\nHo", &all),
+        SniffResult::Incomplete
+    );
+    assert_eq!(
+        sniffed(b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n", &all),
+        Some(Sniffed::new(Protocol::Http, None))
+    );
+    // header 超过 MAX_HEAD_LEN 还没结束时放弃找 Host
+    let mut long = b"GET / HTTP/1.1\r\n".to_vec();
+    while long.len() < http::MAX_HEAD_LEN {
+        long.extend_from_slice(b"X-Padding: 0123456789\r\n");
+    }
+    assert_eq!(
+        sniffed(&long, &all),
+        Some(Sniffed::new(Protocol::Http, None))
+    );
+    assert_eq!(
+        sniff(&[0x16, 0x03, 0x01, 0x02], &all),
+        SniffResult::Incomplete
+    );
+}
+
+// 最长的 client hello 连同 record 头都要在 MAX_SNIFF_LEN 之内读完
+#[test]
+fn test_max_sniff_len() {
+    let sniffers = [SniffConfig {
+        protocol: Protocol::Tls,
+        override_dest: true,
+    }];
+    let record = tls::CLIENT_HELLO_WITH_SERVER_NAME;
+    // padding extension，把握手消息补到 MAX_HANDSHAKE_LEN
+    let padding = tls::MAX_HANDSHAKE_LEN - (record.len() - 5) - 4;
+    let data = tls::append_extension(record, 21, &vec![0; padding]);
+    assert!(data.len() > tls::MAX_HANDSHAKE_LEN);
+    assert!(data.len() <= MAX_SNIFF_LEN);
+    let prefix = &data[..data.len() - 1];
+    assert!(prefix.len() < MAX_SNIFF_LEN);
+    assert_eq!(sniff(prefix, &sniffers), SniffResult::Incomplete);
+    match sniff(&data, &sniffers) {
+        SniffResult::Found(sniffed, _) => {
+            assert_eq!(sniffed.domain.as_deref(), Some("www.google.com"))
+        }
+        result => panic!("unexpected {:?}", result),
+    }
+}
+
+// server 先说话时 client 一个字节都没发，单独计数，不算 truncated
+#[test]
+fn test_record_tls_stats() {
+    let count = |name: &str| {
+        tls::STATS
+            .snapshot()
+            .into_iter()
+            .find(|(n, _)| *n == name)
+            .unwrap()
+            .1
+    };
+    let sniffers = [SniffConfig {
+        protocol: Protocol::Tls,
+        override_dest: true,
+    }];
+    let (no_data, truncated) = (count("no_data"), count("truncated"));
+    record_tls_stats(&SniffResult::Unknown, b"", &sniffers);
+    assert_eq!(count("no_data"), no_data + 1);
+    assert_eq!(count("truncated"), truncated);
+    record_tls_stats(&SniffResult::Unknown, &[0x16, 0x03, 0x01], &sniffers);
+    assert_eq!(count("truncated"), truncated + 1);
+
+    // 认出 http 的连接不算 not_handshake
+    let (other, not_handshake) = (count("other_protocol"), count("not_handshake"));
+    let data = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let both = [
+        SniffConfig {
+            protocol: Protocol::Tls,
+            override_dest: true,
+        },
+        SniffConfig {
+            protocol: Protocol::Http,
+            override_dest: true,
+        },
+    ];
+    let result = sniff(data, &both);
+    assert!(matches!(result, SniffResult::Found(ref s, _) if s.protocol == Protocol::Http));
+    record_tls_stats(&result, data, &sniffers);
+    assert_eq!(count("other_protocol"), other + 1);
+    assert_eq!(count("not_handshake"), not_handshake);
+}
@@ -35,7 +35,7 @@ const SHARED_BUF_SIZE: usize = 1024 * 64;
 const PRIVATE_BUF_SIZE: usize = 1024 * 8;
 const HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
 thread_local! {
-    static SHARED_BUFFER: RefCell<[u8; SHARED_BUF_SIZE]> = RefCell::new([0u8; SHARED_BUF_SIZE]);
+    static SHARED_BUFFER: RefCell<[u8; SHARED_BUF_SIZE]> = const { RefCell::new([0u8; SHARED_BUF_SIZE]) };
 }
 pub struct StreamWithBuffer {
     pub stream: TcpStream,
@@ -128,7 +128,7 @@ impl StreamWithBuffer {
                     self.buf = Some(buf.into_boxed_slice());
                     Poll::Pending
                 })
-            },
+            }
             _ => result,
         }
     }
@@ -166,7 +166,7 @@ impl BiPipe {
         };
         loop {
             if reader.is_empty() && !reader.read_eof {
-                let n = try_poll!(reader.poll_read_to_buffer(ctx));
+                try_poll!(reader.poll_read_to_buffer(ctx));
             }
             while !reader.is_empty() {
                 try_poll!(reader.poll_write_buffer_to(ctx, &mut writer.stream));
@@ -175,7 +175,9 @@ impl BiPipe {
                 match Pin::new(&mut writer.stream).poll_shutdown(ctx) {
                     Poll::Pending => return Poll::Pending,
                     Poll::Ready(Ok(())) => (),
-                    Poll::Ready(Err(err)) => debug!("Failed to shutdown, maybe connect error. Error: {}", err),
+                    Poll::Ready(Err(err)) => {
+                        debug!("Failed to shutdown, maybe connect error. Error: {}", err)
+                    }
                 }
                 // writer 已经关闭
                 // reader 将在另一个 poll_one_side 中作为 writer 被关闭
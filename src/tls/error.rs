@@ -0,0 +1,73 @@
+use std::{error, fmt};
+
+// 解析 tls record 和 client hello 失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsError {
+    // record 的 content type 不是 handshake，一般说明不是 tls
+    NotHandshake,
+    // record 的 major version 不是 3
+    UnknownVersion,
+    // record 的 length 为 0 或者超过 2^14
+    InvalidRecordLength,
+    // 握手消息不是 client hello
+    NotClientHello,
+    // 握手消息超过 MAX_HANDSHAKE_LEN
+    TooLong,
+    // 数据不完整，或者某个长度字段超出了数据
+    Truncated,
+    // client hello 的 legacy_version 不是 3.x
+    UnsupportedVersion,
+    // server_name 不是 utf8
+    InvalidServerName,
+    // encrypted_client_hello extension 的格式不对
+    InvalidEch,
+}
+
+impl TlsError {
+    // 和声明的顺序一致，SniffStats 按下标计数
+    pub const ALL: [TlsError; 9] = [
+        TlsError::NotHandshake,
+        TlsError::UnknownVersion,
+        TlsError::InvalidRecordLength,
+        TlsError::NotClientHello,
+        TlsError::TooLong,
+        TlsError::Truncated,
+        TlsError::UnsupportedVersion,
+        TlsError::InvalidServerName,
+        TlsError::InvalidEch,
+    ];
+
+    // 计数和日志用的名字
+    pub fn name(self) -> &'static str {
+        match self {
+            TlsError::NotHandshake => "not_handshake",
+            TlsError::UnknownVersion => "unknown_version",
+            TlsError::InvalidRecordLength => "invalid_record_length",
+            TlsError::NotClientHello => "not_client_hello",
+            TlsError::TooLong => "too_long",
+            TlsError::Truncated => "truncated",
+            TlsError::UnsupportedVersion => "unsupported_version",
+            TlsError::InvalidServerName => "invalid_server_name",
+            TlsError::InvalidEch => "invalid_ech",
+        }
+    }
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            TlsError::NotHandshake => "not a handshake",
+            TlsError::UnknownVersion => "unknown tls version",
+            TlsError::InvalidRecordLength => "invalid record length",
+            TlsError::NotClientHello => "handshake type isn't a client hello",
+            TlsError::TooLong => "client hello too long",
+            TlsError::Truncated => "truncated client hello",
+            TlsError::UnsupportedVersion => "unsupported client hello version",
+            TlsError::InvalidServerName => "server name isn't utf8",
+            TlsError::InvalidEch => "invalid encrypted client hello extension",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl error::Error for TlsError {}
@@ -0,0 +1,186 @@
+// client hello 指纹，计算时都去掉 GREASE
+// JA3: https://github.com/salesforce/ja3
+// JA4: https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md
+use sha2::{Digest, Sha256};
+
+use super::{is_grease, TlsClientHello, EXT_ALPN, EXT_SERVER_NAME};
+
+impl TlsClientHello {
+    // SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats
+    // 都是十进制，列表内用 - 连接，保持原来的顺序
+    pub fn ja3_string(&self) -> String {
+        let join = |list: &[u16]| {
+            list.iter()
+                .filter(|&&v| !is_grease(v))
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join("-")
+        };
+        let point_formats = self
+            .ec_point_formats
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join("-");
+        format!(
+            "{},{},{},{},{}",
+            self.version,
+            join(&self.cipher_suites),
+            join(&self.extensions),
+            join(&self.supported_groups),
+            point_formats
+        )
+    }
+
+    // ja3_string 的 md5
+    pub fn ja3(&self) -> String {
+        format!("{:x}", md5::compute(self.ja3_string()))
+    }
+
+    // JA4_a: t13d1516h2，协议、版本、有没有 SNI、cipher 数量、extension 数量、ALPN
+    // JA4_b: 排序后的 cipher 的 sha256 前 12 位
+    // JA4_c: 排序后的 extension（不含 SNI 和 ALPN）加上原始顺序的 signature_algorithms
+    pub fn ja4(&self) -> String {
+        self.ja4_with('t')
+    }
+
+    // QUIC 的 client hello 以 q 开头
+    pub fn ja4_quic(&self) -> String {
+        self.ja4_with('q')
+    }
+
+    fn ja4_with(&self, transport: char) -> String {
+        let ciphers = without_grease(&self.cipher_suites);
+        let extensions = without_grease(&self.extensions);
+        let a = format!(
+            "{}{}{}{:02}{:02}{}",
+            transport,
+            ja4_version(self),
+            if self.server_name.is_some() { 'd' } else { 'i' },
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            ja4_alpn(self.raw_alpn.first().map(|alpn| &**alpn)),
+        );
+
+        let mut sorted = ciphers;
+        sorted.sort_unstable();
+        let b = truncated_sha256(&hex_list(&sorted));
+
+        let mut sorted: Vec<u16> = extensions
+            .into_iter()
+            .filter(|&v| v != EXT_SERVER_NAME && v != EXT_ALPN)
+            .collect();
+        sorted.sort_unstable();
+        let c = if sorted.is_empty() {
+            truncated_sha256("")
+        } else if self.signature_algorithms.is_empty() {
+            truncated_sha256(&hex_list(&sorted))
+        } else {
+            let algorithms = without_grease(&self.signature_algorithms);
+            truncated_sha256(&format!("{}_{}", hex_list(&sorted), hex_list(&algorithms)))
+        };
+        format!("{}_{}_{}", a, b, c)
+    }
+}
+
+fn without_grease(list: &[u16]) -> Vec<u16> {
+    list.iter().copied().filter(|&v| !is_grease(v)).collect()
+}
+
+// 有 supported_versions 时取里面最高的版本
+fn ja4_version(hello: &TlsClientHello) -> &'static str {
+    let version = without_grease(&hello.supported_versions)
+        .into_iter()
+        .max()
+        .unwrap_or(hello.version);
+    match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        0xfeff => "d1",
+        0xfefd => "d2",
+        0xfefc => "d3",
+        _ => "00",
+    }
+}
+
+// 第一个 ALPN 的首尾字符，不是字母数字时用十六进制的首尾字符
+fn ja4_alpn(alpn: Option<&[u8]>) -> String {
+    let (first, last) = match alpn {
+        Some([first, .., last]) => (*first, *last),
+        Some([only]) => (*only, *only),
+        _ => return String::from("00"),
+    };
+    if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+        format!("{}{}", first as char, last as char)
+    } else {
+        format!("{:x}{:x}", first >> 4, last & 0x0f)
+    }
+}
+
+// 4 位小写十六进制，逗号连接
+fn hex_list(list: &[u16]) -> String {
+    list.iter()
+        .map(|v| format!("{:04x}", v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn truncated_sha256(input: &str) -> String {
+    if input.is_empty() {
+        return String::from("000000000000");
+    }
+    let mut hash = format!("{:x}", Sha256::digest(input.as_bytes()));
+    hash.truncate(12);
+    hash
+}
+
+// JA3 README 里的例子
+#[test]
+fn test_ja3() {
+    let hello = TlsClientHello {
+        version: 769,
+        cipher_suites: vec![47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4],
+        extensions: vec![0x0a0a, 0, 10, 11],
+        supported_groups: vec![23, 24, 25],
+        ec_point_formats: vec![0],
+        ..TlsClientHello::default()
+    };
+    assert_eq!(
+        hello.ja3_string(),
+        "769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0"
+    );
+    assert_eq!(hello.ja3(), "ada70206e40642a3e4461f35503241d5");
+}
+
+// JA4 技术文档里 t13d1516h2_8daaf6152771_e5627efa2ab1 的例子
+#[test]
+fn test_ja4() {
+    let mut hello = TlsClientHello {
+        server_name: Some(Box::from("example.com")),
+        version: 0x0303,
+        cipher_suites: vec![
+            0x2a2a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+            0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+        ],
+        extensions: vec![
+            0x3a3a, 0x0000, 0x0017, 0xff01, 0x000a, 0x000b, 0x0023, 0x0010, 0x0005, 0x000d, 0x0012,
+            0x0033, 0x002d, 0x002b, 0x001b, 0x4469, 0x0015,
+        ],
+        raw_alpn: vec![Box::from(&b"h2"[..]), Box::from(&b"http/1.1"[..])],
+        supported_versions: vec![0x4a4a, 0x0304, 0x0303],
+        signature_algorithms: vec![
+            0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+        ],
+        ..TlsClientHello::default()
+    };
+    assert_eq!(hello.ja4(), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+    hello.server_name = None;
+    hello.raw_alpn.clear();
+    hello.supported_versions.clear();
+    assert!(hello.ja4().starts_with("t12i151600_8daaf6152771_"));
+    assert_eq!(ja4_alpn(Some(b"\xabc\xcd")), "ad");
+}
@@ -2,21 +2,36 @@ use std::ops::Range;
 use std::str::from_utf8;
 
 use log::debug;
-const EXT_SERVER_NAME: &[u8] = &[0, 0];
+
+mod error;
+mod fingerprint;
+mod stats;
+
+pub use self::error::TlsError;
+pub use self::stats::{SniffStats, STATS};
+
+// https://www.iana.org/assignments/tls-extensiontype-values
+pub const EXT_SERVER_NAME: u16 = 0x0000;
+pub const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+pub const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+pub const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+pub const EXT_ALPN: u16 = 0x0010;
+pub const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+pub const EXT_KEY_SHARE: u16 = 0x0033;
+pub const EXT_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+
 // len_range作为长度，获取长度之内的数据
 // 0x01 0x02 0x03 0x04
 // 0x01 表明长度为1
 // 最后获得 0x02
-fn slice_by_len_at_range(data: &[u8], len_range: Range<usize>) -> Result<&[u8], &'static str> {
-    let len_in_bits = data
-        .get(len_range.clone())
-        .ok_or("no enough data length to decode")?;
+fn slice_by_len_at_range(data: &[u8], len_range: Range<usize>) -> Result<&[u8], TlsError> {
+    let len_in_bits = data.get(len_range.clone()).ok_or(TlsError::Truncated)?;
     let mut actual_len = 0usize;
     for bit in len_in_bits {
         actual_len = actual_len << 8 | (*bit as usize)
     }
     data.get(len_range.end..len_range.end + actual_len)
-        .ok_or("error when get index")
+        .ok_or(TlsError::Truncated)
 }
 
 // 移除 len_range.end 之前的数据
@@ -25,92 +40,306 @@ fn slice_by_len_at_range(data: &[u8], len_range: Range<usize>) -> Result<&[u8],
 // 0x01 0x02 0x03 0x04
 // 0x01 表明长度为1
 // 最后获得 0x03 及之后数据
-fn truncate_before(data: &[u8], len_range: Range<usize>) -> Result<&[u8], &'static str> {
+fn truncate_before(data: &[u8], len_range: Range<usize>) -> Result<&[u8], TlsError> {
     let len = slice_by_len_at_range(data, len_range.clone())?.len();
     Ok(&data[len_range.end + len..])
 }
 
 pub struct TlsRecord<'a> {
-    content_type: u8,
+    pub content_type: u8,
     // struct {
     //     uint8 major;
     //     uint8 minor;
     // } ProtocolVersion;
-    major_version: u8,
-    minor_version: u8,
-    fragment: &'a [u8],
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub fragment: &'a [u8],
 }
 
-// 解析 TlsClientHello，我们当前只关心 server_name
-// https://tools.ietf.org/html/rfc6066#section-3
+// 解析 TlsClientHello，除了 server_name 还保留路由和指纹需要的字段
+// 列表都按 client 发送的顺序，包括 GREASE
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct TlsClientHello {
+    // https://tools.ietf.org/html/rfc6066#section-3
     pub server_name: Option<Box<str>>,
+    // legacy_version，TLS 1.3 里固定是 0x0303，实际版本看 supported_versions
+    pub version: u16,
+    pub cipher_suites: Vec<u16>,
+    pub extensions: Vec<u16>,
+    // https://tools.ietf.org/html/rfc7301#section-3.1
+    // 给路由和日志用，不是 utf-8 的字节替换成 U+FFFD
+    pub alpn: Vec<Box<str>>,
+    // 线上的原始字节，算 JA4 用
+    pub raw_alpn: Vec<Box<[u8]>>,
+    // https://tools.ietf.org/html/rfc8446#section-4.2.1
+    pub supported_versions: Vec<u16>,
+    // https://tools.ietf.org/html/rfc8446#section-4.2.7
+    pub supported_groups: Vec<u16>,
+    // https://tools.ietf.org/html/rfc8422#section-5.1.2
+    pub ec_point_formats: Vec<u8>,
+    // https://tools.ietf.org/html/rfc8446#section-4.2.3
+    pub signature_algorithms: Vec<u16>,
+    // key_share 里每个 KeyShareEntry 的 group
+    // https://tools.ietf.org/html/rfc8446#section-4.2.8
+    pub key_share_groups: Vec<u16>,
+    // encrypted_client_hello extension，有的话 server_name 是 outer 的
+    pub ech: Option<Ech>,
+    // 上面任意一个列表里有 GREASE 值
+    pub has_grease: bool,
+}
+
+// ECHClientHello，真实的 ClientHelloInner 加密在 payload 里
+// client 没有 ECHConfig 时也会发 GREASE ECH，这时 outer 的 server_name 就是真实域名，线上无法区分
+// 路由时按 outer 的 server_name 是不是已知的 public name 来判断，见 routing.ech_public_names
+// https://datatracker.ietf.org/doc/draft-ietf-tls-esni/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ech {
+    Outer {
+        // HpkeSymmetricCipherSuite
+        kdf_id: u16,
+        aead_id: u16,
+        config_id: u8,
+        enc_len: usize,
+        payload_len: usize,
+    },
+    // 只会出现在解密后的 ClientHelloInner 里
+    Inner,
 }
-pub fn parse_tls_record<'a>(data: &'a [u8]) -> Result<TlsRecord<'a>, &'static str> {
-    let fragment = slice_by_len_at_range(&data, 3..5)?;
-    Ok(TlsRecord {
-        content_type: data[0],
-        major_version: data[1],
-        minor_version: data[2],
-        fragment,
-    })
-}
-pub fn parse_client_hello(data: &[u8]) -> Result<TlsClientHello, &'static str> {
-    let TlsRecord {
-        content_type,
-        major_version,
-        minor_version,
-        fragment,
-    } = parse_tls_record(&data)?;
-    if major_version != 3 {
-        return Err("unknow tls version");
-    }
-    if content_type != 22 {
-        return Err("not a handshake");
-    }
-    if fragment.get(0) != Some(&1) {
-        return Err(" Handshake Type isn't a client hello");
+
+// 0x0a0a、0x1a1a ... 0xfafa
+// https://tools.ietf.org/html/rfc8701#section-2
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+pub fn parse_tls_record<'a>(data: &'a [u8]) -> Result<TlsRecord<'a>, TlsError> {
+    let fragment = slice_by_len_at_range(data, 3..5)?;
+    match *data {
+        [content_type, major_version, minor_version, ..] => Ok(TlsRecord {
+            content_type,
+            major_version,
+            minor_version,
+            fragment,
+        }),
+        _ => Err(TlsError::Truncated),
+    }
+}
+
+// 握手消息最多收这么多，超过的不再等，加上抗量子的 key share 一般也只有 2KB 左右
+pub const MAX_HANDSHAKE_LEN: usize = 16 * 1024;
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+// record 的 length 不能超过 2^14
+// https://tools.ietf.org/html/rfc8446#section-5.1
+pub const MAX_RECORD_LEN: usize = 1 << 14;
+
+// 一个握手消息可以分在多个 record 里，拼出第一个完整的握手消息
+// 数据还不够时返回 Ok(None)，调用方继续读
+// 不是 tls 时尽早返回错误，不用等更多数据
+pub fn read_handshake(data: &[u8]) -> Result<Option<Vec<u8>>, TlsError> {
+    let mut message = Vec::new();
+    let mut rest = data;
+    loop {
+        if rest.first().is_some_and(|&t| t != CONTENT_TYPE_HANDSHAKE) {
+            return Err(TlsError::NotHandshake);
+        }
+        if rest.get(1).is_some_and(|&major| major != 3) {
+            return Err(TlsError::UnknownVersion);
+        }
+        if rest.len() < 5 {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        if len == 0 || len > MAX_RECORD_LEN {
+            return Err(TlsError::InvalidRecordLength);
+        }
+        let fragment = match rest.get(5..5 + len) {
+            Some(fragment) => fragment,
+            None => {
+                // 只有部分 record，已经拿到的先用来检查握手头
+                message.extend_from_slice(&rest[5..]);
+                return check_handshake(&message).map(|_| None);
+            }
+        };
+        message.extend_from_slice(fragment);
+        rest = &rest[5 + len..];
+        if let Some(total) = check_handshake(&message)? {
+            if message.len() >= total {
+                message.truncate(total);
+                return Ok(Some(message));
+            }
+        }
+    }
+}
+
+// 返回握手消息的总长度，头还不完整时返回 None
+pub fn check_handshake(message: &[u8]) -> Result<Option<usize>, TlsError> {
+    if message
+        .first()
+        .is_some_and(|&t| t != HANDSHAKE_CLIENT_HELLO)
+    {
+        return Err(TlsError::NotClientHello);
+    }
+    if message.len() < 4 {
+        return Ok(None);
+    }
+    let total = 4 + (u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize);
+    if total > MAX_HANDSHAKE_LEN {
+        return Err(TlsError::TooLong);
+    }
+    Ok(Some(total))
+}
+
+// data 从 record 开始，client hello 不完整时返回错误
+pub fn parse_client_hello(data: &[u8]) -> Result<TlsClientHello, TlsError> {
+    let message = read_handshake(data)?.ok_or(TlsError::Truncated)?;
+    parse_handshake(&message)
+}
+
+// message 是完整的握手消息，从 Handshake Type 开始
+pub fn parse_handshake(message: &[u8]) -> Result<TlsClientHello, TlsError> {
+    if message.first() != Some(&HANDSHAKE_CLIENT_HELLO) {
+        return Err(TlsError::NotClientHello);
     }
     // Handshake Protocol Client Hello Length is 3 bytes
-    let client_hello_body = slice_by_len_at_range(&fragment, 1..4)?;
+    let client_hello_body = slice_by_len_at_range(message, 1..4)?;
     // version: TLS 1.2 (0x0303)
-    if client_hello_body.get(0) != Some(&0x03) {
-        return Err("unsupported TLS version");
-    }
+    let version = match client_hello_body.get(0..2) {
+        Some(&[0x03, minor]) => u16::from_be_bytes([0x03, minor]),
+        _ => return Err(TlsError::UnsupportedVersion),
+    };
+    let mut hello = TlsClientHello {
+        version,
+        ..TlsClientHello::default()
+    };
 
     // Random 32bytes
     // Session ID Length 2 bytes
     // Session ID
     // 34..35 Session ID Length
-    let remaining = truncate_before(&client_hello_body, 34..35)?;
+    let remaining = truncate_before(client_hello_body, 34..35)?;
     // Cipher Suites Length
-    let remaining = truncate_before(&remaining, 0..2)?;
+    hello.cipher_suites = u16_list(slice_by_len_at_range(remaining, 0..2)?);
+    let remaining = truncate_before(remaining, 0..2)?;
     // compression method
-    let remaining = truncate_before(&remaining, 0..1)?;
+    let remaining = truncate_before(remaining, 0..1)?;
+    // TLS 1.2 之前可以没有 extensions
+    if remaining.is_empty() {
+        hello.has_grease = hello.cipher_suites.iter().any(|&v| is_grease(v));
+        return Ok(hello);
+    }
     // extensions length
-    let mut exts = slice_by_len_at_range(&remaining, 0..2)?;
+    let mut exts = slice_by_len_at_range(remaining, 0..2)?;
     // extensions
     // type 2 bytes
     // length 2 bytes
-    let mut server_name = None;
-    while exts.len() > 4 {
-        let ext_type = &exts[0..2];
-        let ext_data = slice_by_len_at_range(&exts, 2..4)?;
+    while !exts.is_empty() {
+        let ext_type = exts
+            .get(0..2)
+            .map(|t| u16::from_be_bytes([t[0], t[1]]))
+            .ok_or(TlsError::Truncated)?;
+        let ext_data = slice_by_len_at_range(exts, 2..4)?;
         // 移除掉当前extension
         // 这样 exts 就以下一次extension开头
-        exts = truncate_before(&exts, 2..4)?;
-        if ext_type == EXT_SERVER_NAME {
-            // server_name extension
-            if ext_data[3] == 0x00 {
-                let raw_name = slice_by_len_at_range(&ext_data, 3..5)?;
-                let raw_name =
-                    from_utf8(&raw_name).map_err(|_| "error when parse from raw data")?;
-                server_name = Some(String::from(raw_name).into_boxed_str());
-                debug!("TLS parser domain: {}", server_name.as_ref().unwrap());
+        exts = truncate_before(exts, 2..4)?;
+        hello.extensions.push(ext_type);
+        parse_extension(&mut hello, ext_type, ext_data)?;
+    }
+    hello.has_grease = [
+        &hello.cipher_suites,
+        &hello.extensions,
+        &hello.supported_versions,
+        &hello.supported_groups,
+        &hello.key_share_groups,
+    ]
+    .iter()
+    .any(|list| list.iter().any(|&v| is_grease(v)));
+    Ok(hello)
+}
+
+fn parse_extension(hello: &mut TlsClientHello, ext_type: u16, data: &[u8]) -> Result<(), TlsError> {
+    match ext_type {
+        EXT_SERVER_NAME => {
+            // ServerNameList 里只有 host_name(0) 一种
+            let list = slice_by_len_at_range(data, 0..2)?;
+            if list.first() == Some(&0x00) {
+                let raw_name = slice_by_len_at_range(list, 1..3)?;
+                let raw_name = from_utf8(raw_name).map_err(|_| TlsError::InvalidServerName)?;
+                hello.server_name = Some(String::from(raw_name).into_boxed_str());
+                debug!("TLS parser domain: {}", raw_name);
+            }
+        }
+        EXT_ALPN => {
+            let mut list = slice_by_len_at_range(data, 0..2)?;
+            while !list.is_empty() {
+                let name = slice_by_len_at_range(list, 0..1)?;
+                // 协议名可以是任意字节，实际用到的都是 ascii
+                hello
+                    .alpn
+                    .push(String::from_utf8_lossy(name).into_owned().into_boxed_str());
+                hello.raw_alpn.push(Box::from(name));
+                list = truncate_before(list, 0..1)?;
+            }
+        }
+        EXT_SUPPORTED_VERSIONS => {
+            hello.supported_versions = u16_list(slice_by_len_at_range(data, 0..1)?);
+        }
+        EXT_SUPPORTED_GROUPS => {
+            hello.supported_groups = u16_list(slice_by_len_at_range(data, 0..2)?);
+        }
+        EXT_EC_POINT_FORMATS => {
+            hello.ec_point_formats = slice_by_len_at_range(data, 0..1)?.to_vec();
+        }
+        EXT_SIGNATURE_ALGORITHMS => {
+            hello.signature_algorithms = u16_list(slice_by_len_at_range(data, 0..2)?);
+        }
+        EXT_KEY_SHARE => {
+            // group 2 bytes, key_exchange length 2 bytes
+            let mut list = slice_by_len_at_range(data, 0..2)?;
+            while !list.is_empty() {
+                let group = list.get(0..2).ok_or(TlsError::Truncated)?;
+                hello
+                    .key_share_groups
+                    .push(u16::from_be_bytes([group[0], group[1]]));
+                list = truncate_before(list, 2..4)?;
             }
         }
+        EXT_ENCRYPTED_CLIENT_HELLO => hello.ech = Some(parse_ech(data)?),
+        _ => (),
     }
-    Ok(TlsClientHello { server_name })
+    Ok(())
+}
+
+// enum { outer(0), inner(1) } ECHClientHelloType;
+// outer 时后面是 cipher_suite(4) config_id(1) enc<0..2^16-1> payload<1..2^16-1>
+fn parse_ech(data: &[u8]) -> Result<Ech, TlsError> {
+    match *data {
+        [0, k0, k1, a0, a1, config_id, ..] => {
+            let enc_len = slice_by_len_at_range(data, 6..8)?.len();
+            let rest = truncate_before(data, 6..8)?;
+            let payload_len = slice_by_len_at_range(rest, 0..2)?.len();
+            if payload_len == 0 {
+                return Err(TlsError::InvalidEch);
+            }
+            Ok(Ech::Outer {
+                kdf_id: u16::from_be_bytes([k0, k1]),
+                aead_id: u16::from_be_bytes([a0, a1]),
+                config_id,
+                enc_len,
+                payload_len,
+            })
+        }
+        [0, ..] => Err(TlsError::Truncated),
+        [1] => Ok(Ech::Inner),
+        _ => Err(TlsError::InvalidEch),
+    }
+}
+
+// 多余的一个字节忽略
+fn u16_list(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|v| u16::from_be_bytes([v[0], v[1]]))
+        .collect()
 }
 
 // struct {
@@ -142,39 +371,322 @@ fn test_parse() {
         0x02, 0x03, 0x03, 0x02, 0x01, 0x02, 0x02, 0x02, 0x03, 0x01, 0x01, 0x00, 0x0f, 0x00, 0x01,
         0x01,
     ];
-    match parse_client_hello(&data) {
-        Ok(ref hello) => {
-            println!("{:?}", &hello.server_name);
-        }
-        Err(err) => {
-            eprintln!("{:?}", err);
-        }
-    }
+    let hello = parse_client_hello(&data).unwrap();
+    assert_eq!(hello.server_name, None);
+    assert_eq!(hello.cipher_suites, [0xc030, 0x00ff]);
+    assert_eq!(hello.extensions, [0x000b, 0x000a, 0x0023, 0x000d, 0x000f]);
+    assert_eq!(hello.ec_point_formats, [0, 1, 2]);
+    assert_eq!(hello.supported_groups.len(), 25);
+    assert_eq!(hello.signature_algorithms.len(), 16);
 }
 
+// www.google.com
+#[cfg(test)]
+pub(crate) const CLIENT_HELLO_WITH_SERVER_NAME: &[u8] = &[
+    0x16, 0x03, 0x01, 0x00, 0xba, 0x01, 0x00, 0x00, 0xb6, 0x03, 0x03, 0xce, 0xf3, 0xc8, 0x77, 0x36,
+    0x6a, 0x81, 0x3b, 0x2f, 0x22, 0xc8, 0xd3, 0x29, 0xed, 0xf8, 0xb6, 0xec, 0xd9, 0x73, 0xfb, 0x76,
+    0x66, 0x6c, 0xbb, 0xa0, 0x50, 0xbd, 0x42, 0x13, 0xd5, 0xc4, 0xf1, 0x00, 0x00, 0x1e, 0xc0, 0x2b,
+    0xc0, 0x2f, 0xcc, 0xa9, 0xcc, 0xa8, 0xc0, 0x2c, 0xc0, 0x30, 0xc0, 0x0a, 0xc0, 0x09, 0xc0, 0x13,
+    0xc0, 0x14, 0x00, 0x33, 0x00, 0x39, 0x00, 0x2f, 0x00, 0x35, 0x00, 0x0a, 0x01, 0x00, 0x00, 0x6f,
+    0x00, 0x00, 0x00, 0x13, 0x00, 0x11, 0x00, 0x00, 0x0e, 0x77, 0x77, 0x77, 0x2e, 0x67, 0x6f, 0x6f,
+    0x67, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x00, 0x17, 0x00, 0x00, 0xff, 0x01, 0x00, 0x01, 0x00,
+    0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00, 0x19, 0x00, 0x0b,
+    0x00, 0x02, 0x01, 0x00, 0x00, 0x23, 0x00, 0x00, 0x00, 0x10, 0x00, 0x0e, 0x00, 0x0c, 0x02, 0x68,
+    0x32, 0x08, 0x68, 0x74, 0x74, 0x70, 0x2f, 0x31, 0x2e, 0x31, 0x00, 0x05, 0x00, 0x05, 0x01, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x18, 0x00, 0x16, 0x04, 0x03, 0x05, 0x03, 0x06, 0x03, 0x08,
+    0x04, 0x08, 0x05, 0x08, 0x06, 0x04, 0x01, 0x05, 0x01, 0x06, 0x01, 0x02, 0x03, 0x02, 0x01,
+];
+
 #[test]
 fn test_parse_with_server_name() {
-    let data = [
-        0x16, 0x03, 0x01, 0x00, 0xba, 0x01, 0x00, 0x00, 0xb6, 0x03, 0x03, 0xce, 0xf3, 0xc8, 0x77,
-        0x36, 0x6a, 0x81, 0x3b, 0x2f, 0x22, 0xc8, 0xd3, 0x29, 0xed, 0xf8, 0xb6, 0xec, 0xd9, 0x73,
-        0xfb, 0x76, 0x66, 0x6c, 0xbb, 0xa0, 0x50, 0xbd, 0x42, 0x13, 0xd5, 0xc4, 0xf1, 0x00, 0x00,
-        0x1e, 0xc0, 0x2b, 0xc0, 0x2f, 0xcc, 0xa9, 0xcc, 0xa8, 0xc0, 0x2c, 0xc0, 0x30, 0xc0, 0x0a,
-        0xc0, 0x09, 0xc0, 0x13, 0xc0, 0x14, 0x00, 0x33, 0x00, 0x39, 0x00, 0x2f, 0x00, 0x35, 0x00,
-        0x0a, 0x01, 0x00, 0x00, 0x6f, 0x00, 0x00, 0x00, 0x13, 0x00, 0x11, 0x00, 0x00, 0x0e, 0x77,
-        0x77, 0x77, 0x2e, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x00, 0x17,
-        0x00, 0x00, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x1d,
-        0x00, 0x17, 0x00, 0x18, 0x00, 0x19, 0x00, 0x0b, 0x00, 0x02, 0x01, 0x00, 0x00, 0x23, 0x00,
-        0x00, 0x00, 0x10, 0x00, 0x0e, 0x00, 0x0c, 0x02, 0x68, 0x32, 0x08, 0x68, 0x74, 0x74, 0x70,
-        0x2f, 0x31, 0x2e, 0x31, 0x00, 0x05, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0d,
-        0x00, 0x18, 0x00, 0x16, 0x04, 0x03, 0x05, 0x03, 0x06, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08,
-        0x06, 0x04, 0x01, 0x05, 0x01, 0x06, 0x01, 0x02, 0x03, 0x02, 0x01,
+    let hello = parse_client_hello(CLIENT_HELLO_WITH_SERVER_NAME).unwrap();
+    assert_eq!(hello.server_name.as_deref(), Some("www.google.com"));
+    assert_eq!(hello.version, 0x0303);
+    assert_eq!(hello.cipher_suites.len(), 15);
+    assert_eq!(hello.cipher_suites[..2], [0xc02b, 0xc02f]);
+    assert_eq!(
+        hello.extensions,
+        [0x0000, 0x0017, 0xff01, 0x000a, 0x000b, 0x0023, 0x0010, 0x0005, 0x000d]
+    );
+    let alpn: Vec<&str> = hello.alpn.iter().map(|a| &**a).collect();
+    assert_eq!(alpn, ["h2", "http/1.1"]);
+    assert_eq!(hello.supported_groups, [0x001d, 0x0017, 0x0018, 0x0019]);
+    assert_eq!(hello.ec_point_formats, [0]);
+    assert_eq!(hello.signature_algorithms.len(), 11);
+    // TLS 1.2 的 client hello
+    assert!(hello.supported_versions.is_empty() && hello.key_share_groups.is_empty());
+    assert!(hello.ech.is_none() && !hello.has_grease);
+    assert!(hello.ja4().starts_with("t12d1509h2_"));
+
+    // 不是 utf-8 的 ALPN 按原始字节算 JA4，不能用替换后的 U+FFFD
+    let mut data = CLIENT_HELLO_WITH_SERVER_NAME.to_vec();
+    let h2 = data.windows(4).position(|w| w == b"\x0c\x02h2").unwrap() + 2;
+    data[h2..h2 + 2].copy_from_slice(&[0xab, 0xcd]);
+    let hello = parse_client_hello(&data).unwrap();
+    assert_eq!(&*hello.alpn[0], "\u{fffd}\u{fffd}");
+    assert_eq!(&*hello.raw_alpn[0], [0xab, 0xcd]);
+    assert!(hello.ja4().starts_with("t12d1509ad_"), "{}", hello.ja4());
+
+    // 最后一个 extension 的长度超出数据时不能越界
+    let mut message = CLIENT_HELLO_WITH_SERVER_NAME[5..].to_vec();
+    let len = message.len();
+    assert_eq!(message[len - 28..len - 24], [0x00, 0x0d, 0x00, 0x18]);
+    message[len - 25] = 0x19;
+    assert_eq!(parse_handshake(&message), Err(TlsError::Truncated));
+}
+
+// 在 record 末尾加一个 extension，同时修改 record、握手消息和 extensions 的长度
+#[cfg(test)]
+pub(crate) fn append_extension(record: &[u8], ext_type: u16, data: &[u8]) -> Vec<u8> {
+    let body = 9;
+    let session_id = body + 34;
+    let cipher_suites = session_id + 1 + record[session_id] as usize;
+    let compression = cipher_suites
+        + 2
+        + u16::from_be_bytes([record[cipher_suites], record[cipher_suites + 1]]) as usize;
+    let extensions = compression + 1 + record[compression] as usize;
+    let mut out = record.to_vec();
+    out.extend_from_slice(&ext_type.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+    let added = 4 + data.len() as u32;
+    for (start, end) in [(3, 5), (6, 9), (extensions, extensions + 2)] {
+        let mut len = [0u8; 4];
+        len[4 - (end - start)..].copy_from_slice(&out[start..end]);
+        let len = (u32::from_be_bytes(len) + added).to_be_bytes();
+        out[start..end].copy_from_slice(&len[4 - (end - start)..]);
+    }
+    out
+}
+
+#[test]
+fn test_parse_ech() {
+    // outer，HKDF-SHA256、AES-128-GCM，config_id 7，enc 32 字节，payload 144 字节
+    let mut ech = vec![0, 0x00, 0x01, 0x00, 0x01, 7, 0x00, 0x20];
+    ech.extend_from_slice(&[0xaa; 32]);
+    ech.extend_from_slice(&[0x00, 0x90]);
+    ech.extend_from_slice(&[0xbb; 144]);
+    let data = append_extension(
+        CLIENT_HELLO_WITH_SERVER_NAME,
+        EXT_ENCRYPTED_CLIENT_HELLO,
+        &ech,
+    );
+    let hello = parse_client_hello(&data).unwrap();
+    assert_eq!(hello.server_name.as_deref(), Some("www.google.com"));
+    assert_eq!(hello.extensions.last(), Some(&EXT_ENCRYPTED_CLIENT_HELLO));
+    assert_eq!(
+        hello.ech,
+        Some(Ech::Outer {
+            kdf_id: 1,
+            aead_id: 1,
+            config_id: 7,
+            enc_len: 32,
+            payload_len: 144,
+        })
+    );
+
+    let parse = |ech: &[u8]| {
+        let data = append_extension(
+            CLIENT_HELLO_WITH_SERVER_NAME,
+            EXT_ENCRYPTED_CLIENT_HELLO,
+            ech,
+        );
+        parse_client_hello(&data).map(|hello| hello.ech)
+    };
+    assert_eq!(parse(&[1]), Ok(Some(Ech::Inner)));
+    assert_eq!(parse(&ech[..ech.len() - 1]), Err(TlsError::Truncated));
+    assert_eq!(
+        parse(&[0, 0, 1, 0, 1, 7, 0, 0, 0, 0]),
+        Err(TlsError::InvalidEch)
+    );
+    assert_eq!(parse(&[0, 0, 1]), Err(TlsError::Truncated));
+    assert_eq!(parse(&[]), Err(TlsError::InvalidEch));
+    assert_eq!(parse(&[2]), Err(TlsError::InvalidEch));
+}
+
+#[test]
+fn test_read_handshake() {
+    let data = CLIENT_HELLO_WITH_SERVER_NAME;
+    let message = &data[5..];
+    // 数据不够时继续等
+    for len in [0, 1, 3, 5, 9, data.len() - 1] {
+        assert_eq!(read_handshake(&data[..len]), Ok(None), "len {}", len);
+    }
+    assert_eq!(read_handshake(data), Ok(Some(message.to_vec())));
+    // 拆成三个 record，第一个 record 连握手头都不完整
+    let mut split = Vec::new();
+    for chunk in [&message[..2], &message[2..100], &message[100..]] {
+        split.extend_from_slice(&[0x16, 0x03, 0x01]);
+        split.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        split.extend_from_slice(chunk);
+    }
+    assert_eq!(read_handshake(&split[..60]), Ok(None));
+    assert_eq!(read_handshake(&split), Ok(Some(message.to_vec())));
+    let hello = parse_client_hello(&split).unwrap();
+    assert_eq!(hello.server_name.as_deref(), Some("www.google.com"));
+    // 后面跟着的数据不影响
+    split.extend_from_slice(b"early data");
+    assert_eq!(read_handshake(&split), Ok(Some(message.to_vec())));
+
+    assert_eq!(
+        read_handshake(b"GET / HTTP/1.1"),
+        Err(TlsError::NotHandshake)
+    );
+    assert_eq!(read_handshake(&[0x16, 0x02]), Err(TlsError::UnknownVersion));
+    assert_eq!(
+        read_handshake(&[0x16, 0x03, 0x01, 0x00, 0x00]),
+        Err(TlsError::InvalidRecordLength)
+    );
+    assert_eq!(
+        read_handshake(&[0x16, 0x03, 0x01, 0x00, 0x01, 0x02]),
+        Err(TlsError::NotClientHello)
+    );
+    // 超过上限的握手消息
+    assert_eq!(
+        read_handshake(&[0x16, 0x03, 0x01, 0x00, 0x04, 0x01, 0x01, 0x00, 0x00]),
+        Err(TlsError::TooLong)
+    );
+    assert_eq!(
+        parse_client_hello(&data[..100]).err(),
+        Some(TlsError::Truncated)
+    );
+}
+
+// 本地起一个 tcp server 抓到的 client hello
+// curl 7.88.1（OpenSSL 3.0）访问 https://www.example.com/
+// node 20.20.2（OpenSSL 3.0）https.get https://www.wikipedia.org/，没有 ALPN
+// rustls 0.19.1 连 crates.io，ALPN h2、http/1.1
+// kubectl 1.32.4（go1.23.8 的 crypto/tls）--tls-server-name=go.example.com，X25519Kyber768Draft00
+// 下面三个是浏览器的抓包，取自 rama-tls 0.4.0 的指纹测试，只有握手消息体，补上了 record 和握手头
+// chrome-grease-single.pcap，最早来自 https://github.com/jabedude/ja3-rs/blob/a30d1bea03d2230b1239d437c3f6af7fb7699338/src/lib.rs#L380
+// brave.pcap，Chromium 内核，访问 tls.peet.ws，带 GREASE、X25519MLKEM768 和 GREASE ECH
+// wireshark_macos_firefox_133_ramaproxy.org.pcap，Firefox 133，X25519MLKEM768 和 ECH
+#[test]
+fn test_captured_client_hello() {
+    // 数据、SNI、ALPN、key share 的 group、ja4 的前缀
+    type Case = (
+        &'static [u8],
+        &'static str,
+        &'static [&'static str],
+        &'static [u16],
+        &'static str,
+    );
+    let cases: &[Case] = &[
+        (
+            include_bytes!("testdata/curl-7.88.1.bin"),
+            "www.example.com",
+            &["h2", "http/1.1"],
+            &[0x001d],
+            "t13d3112h2_",
+        ),
+        (
+            include_bytes!("testdata/node-20.20.2.bin"),
+            "www.wikipedia.org",
+            &[],
+            &[0x001d],
+            "t13d591000_",
+        ),
+        (
+            include_bytes!("testdata/rustls-0.19.1.bin"),
+            "crates.io",
+            &["h2", "http/1.1"],
+            &[0x001d],
+            "t13d1011h2_",
+        ),
+        (
+            include_bytes!("testdata/go-1.23.8.bin"),
+            "go.example.com",
+            &["h2", "http/1.1"],
+            &[0x6399, 0x001d],
+            "t13d1311h2_",
+        ),
+        // ja4 和 rama 的测试里的期望值一致
+        (
+            include_bytes!("testdata/chrome-grease-single.bin"),
+            "googleads.g.doubleclick.net",
+            &["h2", "http/1.1"],
+            &[0x9a9a, 0x001d],
+            "t13d1615h2_46e7e9700bed_45f260be83e2",
+        ),
+        (
+            include_bytes!("testdata/brave-mlkem.bin"),
+            "tls.peet.ws",
+            &["h2", "http/1.1"],
+            &[0xaaaa, 0x11ec, 0x001d],
+            "t13d1517h2_8daaf6152771_b6f405a00624",
+        ),
+        (
+            include_bytes!("testdata/firefox-133.bin"),
+            "ramaproxy.org",
+            &["h2", "http/1.1"],
+            &[0x11ec, 0x001d, 0x0017],
+            "t13d1716h2_5b57614c22b0_eeeea6562960",
+        ),
     ];
-    match parse_client_hello(&data) {
-        Ok(ref hello) => {
-            println!("{:?}", &hello.server_name);
+    for (data, server_name, alpn, key_share_groups, ja4) in cases {
+        let hello = parse_client_hello(data).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some(*server_name));
+        let actual: Vec<&str> = hello.alpn.iter().map(|a| &**a).collect();
+        assert_eq!(&actual, alpn, "{}", server_name);
+        assert!(hello.supported_versions.contains(&0x0304));
+        assert_eq!(&hello.key_share_groups, key_share_groups, "{}", server_name);
+        assert!(hello.ja4().starts_with(ja4), "{}", hello.ja4());
+        // 任意位置截断都不能 panic
+        for len in 0..data.len() {
+            assert_eq!(
+                parse_client_hello(&data[..len]).err(),
+                Some(TlsError::Truncated)
+            );
+            let _ = parse_handshake(&data[5..len.max(5)]);
         }
-        Err(err) => {
-            eprintln!("{:?}", err);
+    }
+}
+
+// 浏览器的 GREASE 和 ECH，以及分在多个 record 里的 client hello
+#[test]
+fn test_browser_client_hello() {
+    let chrome = parse_client_hello(include_bytes!("testdata/chrome-grease-single.bin")).unwrap();
+    assert!(chrome.has_grease && chrome.ech.is_none());
+    assert_eq!(chrome.ja3(), "66918128f1b9b03303d77c6f2eefd128");
+    let firefox = parse_client_hello(include_bytes!("testdata/firefox-133.bin")).unwrap();
+    assert!(!firefox.has_grease);
+    assert!(matches!(firefox.ech, Some(Ech::Outer { .. })));
+
+    // 带 ML-KEM key share 的 client hello 接近 2KB，按 512 字节重新分成多个 record
+    let data: &[u8] = include_bytes!("testdata/brave-mlkem.bin");
+    let brave = parse_client_hello(data).unwrap();
+    assert!(brave.has_grease);
+    assert!(matches!(brave.ech, Some(Ech::Outer { .. })));
+    let mut records = Vec::new();
+    for fragment in data[5..].chunks(512) {
+        records.extend_from_slice(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+        records.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+        records.extend_from_slice(fragment);
+    }
+    assert_eq!(
+        read_handshake(&records).unwrap().as_deref(),
+        Some(&data[5..])
+    );
+    assert_eq!(read_handshake(&records[..records.len() - 1]).unwrap(), None);
+    assert_eq!(parse_client_hello(&records).unwrap(), brave);
+}
+
+// fuzz/corpus 里的输入，包括以前会 panic 的 regression-*
+#[test]
+fn test_fuzz_corpus() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/client_hello");
+    let mut count = 0;
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let data = std::fs::read(&path).unwrap();
+        let _ = parse_tls_record(&data);
+        let _ = parse_handshake(&data);
+        let result = parse_client_hello(&data);
+        let name = path.file_name().unwrap().to_string_lossy();
+        if name.starts_with("regression-") {
+            assert_eq!(result.err(), Some(TlsError::Truncated), "{}", name);
         }
+        count += 1;
     }
+    assert!(count > 0);
 }
@@ -0,0 +1,118 @@
+// 透明代理嗅探 client hello 的结果计数，每个连接只记一次
+// 用来看 SNI 取不出来的比例和原因
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::TlsError;
+
+pub static STATS: SniffStats = SniffStats::new();
+
+pub struct SniffStats {
+    server_name: AtomicU64,
+    no_server_name: AtomicU64,
+    // 没等到任何数据，一般是 server 先说话的协议
+    no_data: AtomicU64,
+    // 嗅探认出了 http、ssh 等其他协议，不是 client hello 解析失败
+    other_protocol: AtomicU64,
+    // 下标和 TlsError::ALL 一致
+    errors: [AtomicU64; TlsError::ALL.len()],
+}
+
+impl SniffStats {
+    pub const fn new() -> SniffStats {
+        // 只用来初始化数组
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        SniffStats {
+            server_name: ZERO,
+            no_server_name: ZERO,
+            no_data: ZERO,
+            other_protocol: ZERO,
+            errors: [ZERO; TlsError::ALL.len()],
+        }
+    }
+
+    // 取出了 client hello，区分有没有 SNI
+    pub fn record_hello(&self, has_server_name: bool) {
+        let counter = if has_server_name {
+            &self.server_name
+        } else {
+            &self.no_server_name
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_no_data(&self) {
+        self.no_data.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_other_protocol(&self) {
+        self.other_protocol.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, err: TlsError) {
+        self.errors[err as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // 名字和当前的值，没有出现过的原因也在里面
+    pub fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut counters = vec![
+            ("server_name", load(&self.server_name)),
+            ("no_server_name", load(&self.no_server_name)),
+            ("no_data", load(&self.no_data)),
+            ("other_protocol", load(&self.other_protocol)),
+        ];
+        for (err, counter) in TlsError::ALL.iter().zip(self.errors.iter()) {
+            counters.push((err.name(), load(counter)));
+        }
+        counters
+    }
+
+    pub fn total(&self) -> u64 {
+        self.snapshot().iter().map(|(_, value)| value).sum()
+    }
+}
+
+impl Default for SniffStats {
+    fn default() -> Self {
+        SniffStats::new()
+    }
+}
+
+// server_name=10 no_server_name=1 truncated=2，只输出不为 0 的
+impl fmt::Display for SniffStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (name, value) in self.snapshot() {
+            if value == 0 {
+                continue;
+            }
+            if !first {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}={}", name, value)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_sniff_stats() {
+    let stats = SniffStats::new();
+    stats.record_hello(false);
+    stats.record_hello(true);
+    stats.record_hello(true);
+    stats.record_error(TlsError::Truncated);
+    stats.record_error(TlsError::NotHandshake);
+    stats.record_no_data();
+    stats.record_other_protocol();
+    assert_eq!(stats.total(), 7);
+    assert_eq!(
+        stats.to_string(),
+        "server_name=2 no_server_name=1 no_data=1 other_protocol=1 not_handshake=1 truncated=1"
+    );
+}
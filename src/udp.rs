@@ -0,0 +1,656 @@
+use std::{
+    collections::HashMap,
+    future::pending,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use log::{debug, log_enabled, warn, Level};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::{
+    client::{Address, Destination},
+    config::Config,
+    protocols::socks5::{self, build_udp_header, parse_udp_header},
+    quic::{self, InitialAssembler},
+    router::Outbound,
+    upstream::{self, UpstreamGuard},
+};
+
+// udp 包最大 64K
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+// 一个 association 里最多跟踪这么多个目标的 QUIC Initial
+const MAX_QUIC_INITIALS: usize = 64;
+// 缓存的每个目标的路由结果，满了就清空重新匹配
+const MAX_ROUTES: usize = 1024;
+
+// v4-mapped v6 地址转回 v4，方便比较和回复 client
+pub(crate) fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        _ => ip,
+    }
+}
+
+pub(crate) fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(canonical_ip(addr.ip()), addr.port())
+}
+
+// 经过 upstream 中转时
+// client 发来的包已经是 socks udp 格式，原样转给 upstream 的 relay 地址即可
+struct UpstreamRelay {
+    // 控制连接关闭 upstream 就会释放 association
+    control: TcpStream,
+    guard: UpstreamGuard,
+}
+
+// 一个 UDP ASSOCIATE 的生命周期，control 是 client 的控制连接
+// control 关闭时 association 结束
+// route 匹配路由规则，先用请求里声明的 DST 选出口，之后每个包再按自己的目标过滤
+// https://tools.ietf.org/html/rfc1928#section-7
+pub async fn associate(
+    mut control: TcpStream,
+    config: Arc<Config>,
+    declared: Destination,
+    route: impl Fn(&Destination) -> Outbound,
+    label: String,
+) -> io::Result<()> {
+    let peer = canonical_addr(control.peer_addr()?);
+    let local_ip = canonical_ip(control.local_addr()?.ip());
+    // client 能连上 control 的地址一定也能收到 udp
+    let relay = UdpSocket::bind((local_ip, 0)).await?;
+    let (outbound, mut upstream) = match connect_upstream(&config, route(&declared)).await {
+        Ok(Some((outbound, upstream))) => (outbound, Some(upstream)),
+        Ok(None) => (bind_direct().await?, None),
+        Err(err) => {
+            let reply = socks5::Reply::error((&err).into()).to_bytes()?;
+            control.write_all(&reply).await?;
+            return Err(err);
+        }
+    };
+    let reply = socks5::build_reply(0x00, &canonical_addr(relay.local_addr()?).into())?;
+    control.write_all(&reply).await?;
+    debug!(
+        "{} udp associate on {}, {}",
+        label,
+        relay.local_addr()?,
+        match upstream {
+            Some(ref upstream) => format!("through upstream {}", upstream.guard.upstream()),
+            None => String::from("direct"),
+        }
+    );
+    let via_upstream = upstream.is_some();
+    // client 在请求里声明的端口，全 0 表示还不知道
+    let declared_port = declared.port;
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut resolved: HashMap<Box<str>, IpAddr> = HashMap::new();
+    // 目标 -> 这个目标的包能不能转发
+    let mut routes: HashMap<String, bool> = HashMap::new();
+    // 目标 -> 还在拼的 client hello，None 表示已经处理过
+    let mut initials: HashMap<String, Option<InitialAssembler>> = HashMap::new();
+    let mut control_buf = [0u8; 64];
+    let mut upstream_control_buf = [0u8; 64];
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut outbound_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            n = control.read(&mut control_buf) => {
+                // control 上不应该再有数据，读到 EOF 或出错就结束
+                match n {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => continue,
+                }
+            }
+            n = async {
+                match upstream {
+                    Some(ref mut upstream) => {
+                        upstream.control.read(&mut upstream_control_buf).await
+                    }
+                    None => pending().await,
+                }
+            } => {
+                match n {
+                    Ok(0) | Err(_) => {
+                        warn!("{} upstream closed the udp association", label);
+                        break;
+                    }
+                    Ok(_) => continue,
+                }
+            }
+            result = relay.recv_from(&mut buf) => {
+                // 单个包的错误不结束 association，只有 control 关闭才结束
+                let (n, from) = match result {
+                    Ok(received) => received,
+                    Err(err) => {
+                        debug!("{} failed to receive udp packet from client: {}", label, err);
+                        continue;
+                    }
+                };
+                let from = canonical_addr(from);
+                // 只接受控制连接那台机器发来的包
+                let port_mismatch = declared_port != 0 && from.port() != declared_port;
+                if from.ip() != peer.ip() || port_mismatch {
+                    debug!("{} drop udp packet from unknown source {}", label, from);
+                    continue;
+                }
+                client_addr = Some(from);
+                let (dest, data) = match parse_udp_header(&buf[..n]) {
+                    Ok(parsed) => parsed,
+                    Err(err) => {
+                        debug!("{} drop udp packet: {}", label, err);
+                        continue;
+                    }
+                };
+                let key = dest.to_string();
+                let allowed = match routes.get(&key) {
+                    Some(&allowed) => allowed,
+                    None => {
+                        if routes.len() >= MAX_ROUTES {
+                            routes.clear();
+                        }
+                        let allowed = udp_allowed(&config, via_upstream, route(&dest));
+                        routes.insert(key, allowed);
+                        allowed
+                    }
+                };
+                if !allowed {
+                    debug!("{} drop udp packet to {} by routing rules", label, dest);
+                    continue;
+                }
+                // 只为 debug 日志解析，按内容认 QUIC Initial，不看端口
+                if log_enabled!(Level::Debug) {
+                    sniff_quic(&mut initials, &label, &dest, data);
+                }
+                let sent = if via_upstream {
+                    outbound.send(&buf[..n]).await
+                } else {
+                    // 解析时不能转发其他包，最多等 connect 超时
+                    let resolved = timeout(config.timeouts.connect, resolve(&mut resolved, &dest))
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(io::Error::new(io::ErrorKind::TimedOut, "resolve timeout"))
+                        });
+                    match resolved {
+                        Ok(target) => {
+                            let target = direct_target(&outbound, target);
+                            outbound.send_to(data, target).await
+                        }
+                        Err(err) => Err(err),
+                    }
+                };
+                if let Err(err) = sent {
+                    debug!("{} failed to send udp packet to {}: {}", label, dest, err);
+                }
+            }
+            result = outbound.recv_from(&mut outbound_buf) => {
+                // 连接过的 upstream socket 会收到前面某个包的 ICMP 错误（ECONNREFUSED）
+                let (n, from) = match result {
+                    Ok(received) => received,
+                    Err(err) => {
+                        debug!("{} failed to receive udp packet: {}", label, err);
+                        continue;
+                    }
+                };
+                let client_addr = match client_addr {
+                    Some(addr) => addr,
+                    None => continue,
+                };
+                let sent = if via_upstream {
+                    // upstream 已经加好了 socks udp header
+                    relay.send_to(&outbound_buf[..n], client_addr).await
+                } else {
+                    let mut packet = Vec::with_capacity(n + 22);
+                    match build_udp_header(&mut packet, &canonical_addr(from).into()) {
+                        Ok(()) => {
+                            packet.extend_from_slice(&outbound_buf[..n]);
+                            relay.send_to(&packet, client_addr).await
+                        }
+                        Err(err) => Err(err),
+                    }
+                };
+                if let Err(err) = sent {
+                    debug!("{} failed to send udp packet to client: {}", label, err);
+                }
+            }
+        }
+    }
+    debug!("{} udp associate closed", label);
+    Ok(())
+}
+
+// association 建立后出口不会再变，每个包按目标匹配的结果只决定转发还是丢掉
+// reject 的包总是丢掉；直连时命中 upstream 的包也丢掉，不能绕过 upstream 直接发出去
+// 经过 upstream 时命中 direct 的包仍然交给 upstream
+fn udp_allowed(config: &Config, via_upstream: bool, outbound: Outbound) -> bool {
+    match outbound {
+        Outbound::Reject => false,
+        _ if via_upstream => true,
+        Outbound::Direct => true,
+        // 和 association 一样，没有支持 udp 的 upstream 时 proxy 直接发出去
+        Outbound::Proxy => !config.upstreams.iter().any(|u| u.udp),
+        Outbound::Upstream(_) => false,
+    }
+}
+
+// 只用于诊断：把 QUIC client hello 里的 SNI、ALPN 记到 debug 日志里
+// 嗅探的结果不参与路由，也不进 Sniffed
+fn sniff_quic(
+    initials: &mut HashMap<String, Option<InitialAssembler>>,
+    label: &str,
+    dest: &Destination,
+    data: &[u8],
+) {
+    if !quic::is_initial(data) {
+        return;
+    }
+    let key = dest.to_string();
+    if !initials.contains_key(&key) && initials.len() >= MAX_QUIC_INITIALS {
+        return;
+    }
+    let assembler = match initials
+        .entry(key.clone())
+        .or_insert_with(|| Some(InitialAssembler::default()))
+    {
+        Some(assembler) => assembler,
+        None => return,
+    };
+    match assembler.push(data) {
+        Ok(None) => return,
+        Ok(Some(hello)) => debug!(
+            "{} {} sniffed quic {:?} alpn {:?} ja4 {}",
+            label,
+            dest,
+            hello.server_name,
+            hello.alpn,
+            hello.ja4_quic()
+        ),
+        Err(err) => debug!("{} {} failed to sniff quic: {}", label, dest, err),
+    }
+    initials.insert(key, None);
+}
+
+// proxy 按 upstream_policy 的顺序找一个支持 udp 的 upstream
+// 没有配置支持 udp 的 upstream 或者 direct 时返回 None，直接发出去
+async fn connect_upstream(
+    config: &Arc<Config>,
+    outbound: Outbound,
+) -> io::Result<Option<(UdpSocket, UpstreamRelay)>> {
+    let candidates = match upstream::outbound_candidates(config, outbound, |u| u.udp, "udp")? {
+        Some(candidates) => candidates,
+        None => return Ok(None),
+    };
+    let mut last_err = None;
+    for index in candidates {
+        let upstream = &config.upstreams[index];
+        if !upstream.udp {
+            continue;
+        }
+        let result = timeout(config.timeouts.handshake, async {
+            let mut control = timeout(config.timeouts.connect, TcpStream::connect(upstream.addr))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timeout"))??;
+            let bind = socks5::udp_associate(&mut control, upstream.credentials()).await?;
+            let relay_addr = match bind.host {
+                // 回复全 0 时用 upstream 自己的地址
+                Address::Ip(ip) if ip.is_unspecified() => {
+                    SocketAddr::new(upstream.addr.ip(), bind.port)
+                }
+                Address::Ip(ip) => SocketAddr::new(ip, bind.port),
+                Address::Domain(ref domain) => lookup_host((domain.as_ref(), bind.port))
+                    .await?
+                    .next()
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "relay address not found")
+                    })?,
+            };
+            let local: SocketAddr = match relay_addr {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let socket = UdpSocket::bind(local).await?;
+            socket.connect(relay_addr).await?;
+            Ok::<_, io::Error>((socket, control))
+        })
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "udp associate timeout",
+            ))
+        });
+        match result {
+            Ok((socket, control)) => {
+                let relay = UpstreamRelay {
+                    control,
+                    guard: UpstreamGuard::new(config.clone(), index),
+                };
+                return Ok(Some((socket, relay)));
+            }
+            Err(err) => {
+                warn!("upstream {} udp associate failed: {}", upstream, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    match last_err {
+        Some(err) => Err(err),
+        None => Ok(None),
+    }
+}
+
+// 优先用双栈 socket，v4 目标通过 v4-mapped 地址发送
+async fn bind_direct() -> io::Result<UdpSocket> {
+    match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await {
+        Ok(socket) => Ok(socket),
+        Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await,
+    }
+}
+
+fn direct_target(socket: &UdpSocket, target: SocketAddr) -> SocketAddr {
+    match (socket.local_addr(), target) {
+        (Ok(SocketAddr::V6(_)), SocketAddr::V4(v4)) => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        _ => target,
+    }
+}
+
+// 同一个 association 里的域名只解析一次
+async fn resolve(
+    cache: &mut HashMap<Box<str>, IpAddr>,
+    dest: &Destination,
+) -> io::Result<SocketAddr> {
+    let domain = match dest.host {
+        Address::Ip(ip) => return Ok(SocketAddr::new(ip, dest.port)),
+        Address::Domain(ref domain) => domain,
+    };
+    if let Some(ip) = cache.get(domain) {
+        return Ok(SocketAddr::new(*ip, dest.port));
+    }
+    let addr = lookup_host((domain.as_ref(), dest.port))
+        .await?
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} not resolved", domain))
+        })?;
+    cache.insert(domain.clone(), addr.ip());
+    Ok(addr)
+}
+
+// 不经过 upstream：client -> relay -> echo server -> relay -> client
+#[tokio::test]
+async fn test_associate_direct() {
+    use std::{str::FromStr, time::Duration};
+    use tokio::net::TcpListener;
+
+    let echo = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        loop {
+            let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..n], from).await.unwrap();
+        }
+    });
+    // 没有人监听的端口
+    let closed = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut control = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    // 规则选了 direct 时不碰 upstream，即使它支持 udp
+    let config = Config::from_str("upstreams:\n  - addr: 127.0.0.1:1\n    udp: true\n").unwrap();
+    let any: SocketAddr = (Ipv4Addr::UNSPECIFIED, 0).into();
+    let task = tokio::spawn(associate(
+        accepted,
+        Arc::new(config),
+        any.into(),
+        |_: &Destination| Outbound::Direct,
+        String::from("test"),
+    ));
+    let relay_addr = match socks5::read_reply(&mut control).await.unwrap() {
+        Destination {
+            host: Address::Ip(ip),
+            port,
+        } => SocketAddr::new(ip, port),
+        bound => panic!("unexpected relay address {}", bound),
+    };
+
+    let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    // 发不到的包不影响后面的包
+    for (dest, data) in [(closed, b"lost"), (echo_addr, b"ping")] {
+        let mut packet = Vec::new();
+        build_udp_header(&mut packet, &dest.into()).unwrap();
+        packet.extend_from_slice(data);
+        client.send_to(&packet, relay_addr).await.unwrap();
+    }
+    let mut buf = [0u8; 1024];
+    let n = timeout(Duration::from_secs(5), client.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    let (from, data) = parse_udp_header(&buf[..n]).unwrap();
+    assert_eq!(from, echo_addr.into());
+    assert_eq!(data, b"ping");
+
+    // control 关闭时 association 结束
+    drop(control);
+    timeout(Duration::from_secs(5), task)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+// 每个包按自己的目标匹配路由规则，不只看请求里声明的 DST
+#[tokio::test]
+async fn test_associate_route_per_datagram() {
+    use crate::router::{Router, Session};
+    use std::{str::FromStr, time::Duration};
+    use tokio::net::TcpListener;
+
+    let echo = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        loop {
+            let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..n], from).await.unwrap();
+        }
+    });
+    let rejected = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let proxied = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut config = Config::from_str(&format!(
+        r#"
+listeners:
+  - port: 1080
+upstreams:
+  - addr: 127.0.0.1:1
+    udp: true
+routing:
+  rules:
+    - port: ["{}"]
+      outbound: reject
+    - port: ["{}"]
+      outbound: proxy
+  final: direct
+"#,
+        rejected.local_addr().unwrap().port(),
+        proxied.local_addr().unwrap().port()
+    ))
+    .unwrap();
+    config.router = Router::new(&config).unwrap();
+    let config = Arc::new(config);
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut control = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (accepted, src) = listener.accept().await.unwrap();
+    let router_config = config.clone();
+    let route = move |dest: &Destination| {
+        let session = Session {
+            dest,
+            src,
+            inbound: "",
+            user: None,
+            sniffed: None,
+        };
+        router_config.router.route(&session).0
+    };
+    let any: SocketAddr = (Ipv4Addr::UNSPECIFIED, 0).into();
+    let task = tokio::spawn(associate(
+        accepted,
+        config,
+        any.into(),
+        route,
+        String::from("test"),
+    ));
+    let relay_addr = match socks5::read_reply(&mut control).await.unwrap() {
+        Destination {
+            host: Address::Ip(ip),
+            port,
+        } => SocketAddr::new(ip, port),
+        bound => panic!("unexpected relay address {}", bound),
+    };
+
+    let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    // association 是直连的，命中 proxy 的包也不能直接发出去
+    let targets = [
+        rejected.local_addr().unwrap(),
+        proxied.local_addr().unwrap(),
+        echo_addr,
+    ];
+    for dest in targets {
+        let mut packet = Vec::new();
+        build_udp_header(&mut packet, &dest.into()).unwrap();
+        packet.extend_from_slice(b"ping");
+        client.send_to(&packet, relay_addr).await.unwrap();
+    }
+    let mut buf = [0u8; 1024];
+    let n = timeout(Duration::from_secs(5), client.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    let (from, data) = parse_udp_header(&buf[..n]).unwrap();
+    assert_eq!(from, echo_addr.into());
+    assert_eq!(data, b"ping");
+    // 按顺序处理，echo 回来时前两个包已经丢掉了
+    for socket in [&rejected, &proxied] {
+        let received = timeout(Duration::from_millis(100), socket.recv(&mut buf)).await;
+        assert!(received.is_err());
+    }
+
+    drop(control);
+    timeout(Duration::from_secs(5), task)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+// 经过 upstream 时 outbound 是 connect 过的 socket
+// upstream 的 relay 不可达时内核把 ICMP 错误记在 socket 上，下一次 recv 返回 ECONNREFUSED
+#[tokio::test]
+async fn test_associate_upstream_unreachable() {
+    use std::{str::FromStr, time::Duration};
+    use tokio::{net::TcpListener, time::sleep};
+
+    let upstream_relay = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let relay_addr = upstream_relay.local_addr().unwrap();
+    let upstream = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let config = Config::from_str(&format!(
+        "upstreams:\n  - addr: {}\n    udp: true\n",
+        upstream.local_addr().unwrap()
+    ))
+    .unwrap();
+    let upstream = tokio::spawn(async move {
+        let (mut stream, _) = upstream.accept().await.unwrap();
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        stream.write_all(&[0x05, 0x00]).await.unwrap();
+        // 0.0.0.0:0
+        let mut request = [0u8; 10];
+        stream.read_exact(&mut request).await.unwrap();
+        let reply = socks5::build_reply(0x00, &relay_addr.into()).unwrap();
+        stream.write_all(&reply).await.unwrap();
+        stream
+    });
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut control = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    let any: SocketAddr = (Ipv4Addr::UNSPECIFIED, 0).into();
+    let task = tokio::spawn(associate(
+        accepted,
+        Arc::new(config),
+        any.into(),
+        |_: &Destination| Outbound::Proxy,
+        String::from("test"),
+    ));
+    let bound = socks5::read_reply(&mut control).await.unwrap();
+    let relay = match bound.host {
+        Address::Ip(ip) => SocketAddr::new(ip, bound.port),
+        _ => panic!("unexpected relay address {}", bound),
+    };
+    let _upstream_control = upstream.await.unwrap();
+
+    let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let target: SocketAddr = (Ipv4Addr::LOCALHOST, 53).into();
+    let mut packet = Vec::new();
+    build_udp_header(&mut packet, &target.into()).unwrap();
+    packet.extend_from_slice(b"ping");
+    let mut buf = [0u8; 1024];
+    client.send_to(&packet, relay).await.unwrap();
+    let (_, outbound) = timeout(Duration::from_secs(5), upstream_relay.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+
+    // relay 关掉后再发一个包，ICMP port unreachable 留在 outbound 上
+    drop(upstream_relay);
+    client.send_to(&packet, relay).await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+    // relay 恢复，发来的包让 outbound 可读，第一次 recv 先拿到上面的错误
+    let upstream_relay = UdpSocket::bind(relay_addr).await.unwrap();
+    upstream_relay.send_to(&packet, outbound).await.unwrap();
+    let n = timeout(Duration::from_secs(5), client.recv(&mut buf))
+        .await
+        .expect("association ended after the icmp error")
+        .unwrap();
+    assert_eq!(&buf[..n], &packet[..]);
+
+    drop(control);
+    timeout(Duration::from_secs(5), task)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+// 按内容认 QUIC Initial，不是 443 端口也解析，不是 QUIC 的包不跟踪
+#[test]
+fn test_sniff_quic() {
+    let mut initials = HashMap::new();
+    let dest: Destination = ("example.com", 8443).into();
+    sniff_quic(&mut initials, "test", &dest, b"\x00\x01dns query");
+    assert!(initials.is_empty());
+    let packet = include_bytes!("quic/testdata/rfc9369-client-initial.bin");
+    sniff_quic(&mut initials, "test", &dest, packet);
+    // 解析完的目标记为 None，之后的包不再解析
+    assert!(matches!(initials.get("example.com:8443"), Some(None)));
+}
@@ -0,0 +1,258 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use log::warn;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::protocols::{socks5, Credentials};
+use crate::router::Outbound;
+
+// 上游代理 server
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Upstream {
+    #[serde(default)]
+    pub name: String,
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub protocol: Protocol,
+    // socks5 用 RFC 1929，http 用 Basic 认证，两个需要同时配置
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // upstream 是否支持 UDP ASSOCIATE
+    #[serde(default)]
+    pub udp: bool,
+    // upstream 是否支持 BIND
+    #[serde(default)]
+    pub bind: bool,
+    // 当前经过这个 upstream 的连接数，给 least-connections 用
+    #[serde(skip)]
+    active: AtomicUsize,
+}
+
+// upstream 说的协议
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Socks5,
+    // 用 CONNECT 建立隧道，不支持 udp 和 BIND
+    Http,
+}
+
+// 选择第一个尝试的 upstream，失败后按配置顺序尝试剩下的
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    // 总是从第一个开始
+    #[default]
+    Failover,
+    RoundRobin,
+    Random,
+    LeastConnections,
+}
+
+impl Upstream {
+    pub fn new(addr: SocketAddr) -> Self {
+        Upstream {
+            name: String::new(),
+            addr,
+            protocol: Protocol::Socks5,
+            username: None,
+            password: None,
+            udp: false,
+            bind: false,
+            active: AtomicUsize::new(0),
+        }
+    }
+    pub fn credentials(&self) -> Option<Credentials<'_>> {
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => Some((username, password)),
+            _ => None,
+        }
+    }
+    pub fn validate(&self) -> Result<(), String> {
+        if self.protocol != Protocol::Socks5 && (self.udp || self.bind) {
+            return Err(format!(
+                "upstream {}: udp and bind are only supported by socks5 upstreams",
+                self
+            ));
+        }
+        match (&self.username, &self.password) {
+            (None, None) => Ok(()),
+            (Some(username), Some(password)) => socks5::validate_credentials(username, password)
+                .map_err(|err| format!("upstream {}: {}", self, err)),
+            _ => Err(format!(
+                "upstream {}: username and password must be configured together",
+                self
+            )),
+        }
+    }
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Socks5 => write!(f, "socks5"),
+            Protocol::Http => write!(f, "http"),
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}({})", self.name, self.addr)
+        }
+    }
+}
+
+// 返回本次连接尝试 upstream 的顺序，存的是 config.upstreams 的下标
+pub fn candidates(config: &Config) -> Vec<usize> {
+    let upstreams = &config.upstreams;
+    if upstreams.is_empty() {
+        return Vec::new();
+    }
+    let first = match config.upstream_policy {
+        Policy::Failover => 0,
+        Policy::RoundRobin => {
+            config.next_upstream.fetch_add(1, Ordering::Relaxed) % upstreams.len()
+        }
+        Policy::Random => rand::thread_rng().gen_range(0..upstreams.len()),
+        Policy::LeastConnections => upstreams
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, upstream)| upstream.active_connections())
+            .map(|(i, _)| i)
+            .unwrap_or(0),
+    };
+    let mut order = Vec::with_capacity(upstreams.len());
+    order.push(first);
+    order.extend((0..upstreams.len()).filter(|&i| i != first));
+    order
+}
+
+// udp associate 和 bind 按路由选出的 outbound 决定尝试哪些 upstream
+// reject 回复 connection not allowed，direct 返回 None 在本机处理
+// 指定的 upstream 不支持这个命令时报错，不退回直连
+pub fn outbound_candidates(
+    config: &Config,
+    outbound: Outbound,
+    supports: impl Fn(&Upstream) -> bool,
+    command: &str,
+) -> io::Result<Option<Vec<usize>>> {
+    match outbound {
+        Outbound::Reject => Err(socks5::ReplyError::NotAllowed.into()),
+        Outbound::Direct => Ok(None),
+        Outbound::Upstream(index) if supports(&config.upstreams[index]) => Ok(Some(vec![index])),
+        Outbound::Upstream(index) => {
+            warn!(
+                "upstream {} does not support {}",
+                config.upstreams[index], command
+            );
+            Err(socks5::ReplyError::CommandNotSupported.into())
+        }
+        Outbound::Proxy => Ok(Some(candidates(config))),
+    }
+}
+
+// 连接建立后持有，drop 时把 upstream 的连接数减回去
+pub struct UpstreamGuard {
+    config: Arc<Config>,
+    index: usize,
+}
+
+impl UpstreamGuard {
+    pub fn new(config: Arc<Config>, index: usize) -> Self {
+        config.upstreams[index]
+            .active
+            .fetch_add(1, Ordering::Relaxed);
+        UpstreamGuard { config, index }
+    }
+    pub fn upstream(&self) -> &Upstream {
+        &self.config.upstreams[self.index]
+    }
+}
+
+impl Drop for UpstreamGuard {
+    fn drop(&mut self) {
+        self.config.upstreams[self.index]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+fn config_with_policy(policy: Policy) -> Config {
+    let mut config = Config {
+        upstream_policy: policy,
+        ..Config::default()
+    };
+    for port in 1080..1083 {
+        config
+            .upstreams
+            .push(Upstream::new(SocketAddr::from(([127, 0, 0, 1], port))));
+    }
+    config
+}
+
+#[test]
+fn test_validate_credentials() {
+    let mut upstream = Upstream::new(SocketAddr::from(([127, 0, 0, 1], 1080)));
+    assert!(upstream.validate().is_ok());
+    assert!(upstream.credentials().is_none());
+    upstream.username = Some(String::from("user"));
+    assert!(upstream.validate().is_err());
+    upstream.password = Some(String::from("pass"));
+    assert!(upstream.validate().is_ok());
+    assert_eq!(upstream.credentials(), Some(("user", "pass")));
+    upstream.password = Some("x".repeat(256));
+    assert!(upstream.validate().is_err());
+    upstream.password = Some(String::from("pass"));
+    upstream.protocol = Protocol::Http;
+    assert!(upstream.validate().is_ok());
+    upstream.udp = true;
+    assert!(upstream.validate().is_err());
+}
+
+#[test]
+fn test_failover_order() {
+    let config = config_with_policy(Policy::Failover);
+    assert_eq!(candidates(&config), [0, 1, 2]);
+    assert_eq!(candidates(&config), [0, 1, 2]);
+}
+
+#[test]
+fn test_round_robin_order() {
+    let config = config_with_policy(Policy::RoundRobin);
+    assert_eq!(candidates(&config), [0, 1, 2]);
+    assert_eq!(candidates(&config), [1, 0, 2]);
+    assert_eq!(candidates(&config), [2, 0, 1]);
+    assert_eq!(candidates(&config), [0, 1, 2]);
+}
+
+#[test]
+fn test_least_connections_order() {
+    let config = Arc::new(config_with_policy(Policy::LeastConnections));
+    let first = UpstreamGuard::new(config.clone(), 0);
+    let _second = UpstreamGuard::new(config.clone(), 1);
+    assert_eq!(candidates(&config), [2, 0, 1]);
+    let _third = UpstreamGuard::new(config.clone(), 2);
+    let _fourth = UpstreamGuard::new(config.clone(), 2);
+    drop(first);
+    assert_eq!(candidates(&config), [0, 1, 2]);
+    assert_eq!(config.upstreams[2].active_connections(), 2);
+}
@@ -62,15 +62,18 @@ where
                 .map_ok(|_| b.filled().len()));
             if n == 0 {
                 self.is_eof = true;
-            }else {
+            } else {
                 self.pos = 0;
                 self.cap = n;
             }
             while self.pos <= self.cap {
                 let me = &mut *self;
-                let n = ready!(Pin::new(&mut me.writer).poll_write(ctx, &me.buf[me.pos .. me.cap]));
+                let n = ready!(Pin::new(&mut me.writer).poll_write(ctx, &me.buf[me.pos..me.cap]));
                 if n == 0 {
-                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "write zero")));
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "write zero",
+                    )));
                 }
                 me.pos += n;
             }
@@ -80,7 +83,7 @@ where
                 return Poll::Ready(Ok(self.pos));
             }
         }
-        return Poll::Ready(Ok(0));
+        Poll::Ready(Ok(0))
     }
 }
 pub fn copy_from_to<'a, R, W>(reader: &'a mut R, writer: &'a mut W) -> Copy<'a, R, W>